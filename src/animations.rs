@@ -0,0 +1,206 @@
+use image::DynamicImage;
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{self, mpsc::UnboundedSender},
+    time::{Interval, MissedTickBehavior, interval, sleep},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    TOKENS, TRACKER, config::config, mappings::Kind, queue::ImageCommand, watcher::id_to_serial,
+};
+
+/// Images of one frame fitted to their hardware positions, and how long to show them
+pub struct AnimationFrame {
+    pub uploads: Vec<(u8, DynamicImage)>,
+    pub delay: Duration,
+}
+
+/// Running animations of all devices, keyed by device id and then UI position
+static ANIMATIONS: LazyLock<Mutex<HashMap<String, HashMap<u8, CancellationToken>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Frame slots of every device with animations, handed out at most `animation_fps` times a
+/// second, so animations can't saturate the USB link
+static LIMITERS: LazyLock<Mutex<HashMap<String, Arc<sync::Mutex<Interval>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Returns frame limiter shared by all animations of a device
+fn limiter(id: &str) -> Arc<sync::Mutex<Interval>> {
+    LIMITERS
+        .lock()
+        .unwrap()
+        .entry(id.to_string())
+        .or_insert_with(|| {
+            let fps = config().for_serial(id_to_serial(id)).animation_fps;
+            Arc::new(sync::Mutex::new(frame_slots(fps)))
+        })
+        .clone()
+}
+
+fn frame_slots(fps: u32) -> Interval {
+    let mut slots = interval(Duration::from_secs(1) / fps.max(1));
+    // Slots missed while nothing was playing are not made up for with a burst
+    slots.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    slots
+}
+
+/// Stops animation playing at a UI position, if there is one
+pub fn stop_animation(id: &str, position: u8) {
+    let mut animations = ANIMATIONS.lock().unwrap();
+
+    if let Some(token) = animations
+        .get_mut(id)
+        .and_then(|positions| positions.remove(&position))
+    {
+        log::debug!("Stopping animation at position={} on {}", position, id);
+        token.cancel();
+    }
+}
+
+/// Stops all animations of a device
+pub fn stop_animations(id: &str) {
+    if let Some(positions) = ANIMATIONS.lock().unwrap().remove(id) {
+        for token in positions.values() {
+            token.cancel();
+        }
+    }
+
+    LIMITERS.lock().unwrap().remove(id);
+}
+
+/// Starts playing frames at a UI position, replacing whatever was playing there
+pub async fn start_animation(
+    id: &str,
+    position: u8,
+    kind: Kind,
+    queue: UnboundedSender<ImageCommand>,
+    frames: Vec<AnimationFrame>,
+) {
+    // Animations die with their device
+    let Some(token) = TOKENS.read().await.get(id).map(|token| token.child_token()) else {
+        return;
+    };
+
+    stop_animation(id, position);
+
+    log::debug!(
+        "Starting animation of {} frames at position={} on {}",
+        frames.len(),
+        position,
+        id
+    );
+
+    ANIMATIONS
+        .lock()
+        .unwrap()
+        .entry(id.to_string())
+        .or_default()
+        .insert(position, token.clone());
+
+    let limiter = limiter(id);
+
+    TRACKER
+        .lock()
+        .await
+        .spawn(animation_task(kind, queue, frames, limiter, token));
+}
+
+async fn animation_task(
+    kind: Kind,
+    queue: UnboundedSender<ImageCommand>,
+    frames: Vec<AnimationFrame>,
+    limiter: Arc<sync::Mutex<Interval>>,
+    token: CancellationToken,
+) {
+    for frame in frames.iter().cycle() {
+        // Animations of a device take turns, as the mutex queues them in order
+        tokio::select! {
+            _ = async { limiter.lock().await.tick().await } => {},
+            _ = token.cancelled() => return,
+        }
+
+        {
+            // Checked under the lock, so no frame can sneak in after whatever replaced the animation
+            let _animations = ANIMATIONS.lock().unwrap();
+            if token.is_cancelled() {
+                return;
+            }
+
+            for (hw_pos, image) in &frame.uploads {
+                let command = ImageCommand::Set {
+                    hw_pos: *hw_pos,
+                    format: kind.image_format_for(*hw_pos),
+                    image: image.clone(),
+                };
+
+                // Queue is gone once the device is
+                if queue.send(command).is_err() {
+                    return;
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = sleep(frame.delay) => {},
+            _ = token.cancelled() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappings::KINDS;
+    use image::RgbImage;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[tokio::test(start_paused = true)]
+    async fn animations_of_a_device_share_frame_rate() {
+        const FPS: u32 = 10;
+
+        let kind = KINDS.first().unwrap().clone();
+        let (queue, mut commands) = unbounded_channel();
+        let limiter = Arc::new(sync::Mutex::new(frame_slots(FPS)));
+        let token = CancellationToken::new();
+
+        // Frames asking for a much higher rate than the cap, at every key
+        for hw_pos in 0..4 {
+            let frames = vec![AnimationFrame {
+                uploads: vec![(hw_pos, DynamicImage::ImageRgb8(RgbImage::new(1, 1)))],
+                delay: Duration::from_millis(1),
+            }];
+            tokio::spawn(animation_task(
+                kind.clone(),
+                queue.clone(),
+                frames,
+                limiter.clone(),
+                token.clone(),
+            ));
+        }
+
+        // Time only moves when every task waits, so this is exact: slots at 0, 100, ... 400ms
+        sleep(Duration::from_millis(450)).await;
+        token.cancel();
+
+        let mut sent = Vec::new();
+        while let Ok(ImageCommand::Set { hw_pos, .. }) = commands.try_recv() {
+            sent.push(hw_pos);
+        }
+
+        assert_eq!(sent.len(), 5);
+        // Every animation gets its turn
+        for hw_pos in 0..4 {
+            assert!(
+                sent.contains(&hw_pos),
+                "{} never played in {:?}",
+                hw_pos,
+                sent
+            );
+        }
+    }
+}
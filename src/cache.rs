@@ -0,0 +1,131 @@
+use image::DynamicImage;
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{LazyLock, Mutex},
+};
+
+/// What a hardware image target currently shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shown {
+    Cleared,
+    /// Hash of the pixels uploaded to the target
+    Image(u64),
+}
+
+impl Shown {
+    pub fn image(image: &DynamicImage) -> Self {
+        let mut hasher = DefaultHasher::new();
+        (image.width(), image.height()).hash(&mut hasher);
+        image.as_bytes().hash(&mut hasher);
+
+        Shown::Image(hasher.finish())
+    }
+}
+
+/// Contents of hardware image targets of all devices, keyed by device id.
+/// Targets missing from the map are in unknown state and always get uploaded
+static SHOWN: LazyLock<Mutex<HashMap<String, HashMap<u8, Shown>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Returns true if the target already shows this, so uploading it again can be skipped
+pub fn is_shown(id: &str, hw_pos: u8, shown: Shown) -> bool {
+    SHOWN
+        .lock()
+        .unwrap()
+        .get(id)
+        .and_then(|targets| targets.get(&hw_pos))
+        .is_some_and(|current| *current == shown)
+}
+
+/// Records what was successfully uploaded to the target
+pub fn record_shown(id: &str, hw_pos: u8, shown: Shown) {
+    SHOWN
+        .lock()
+        .unwrap()
+        .entry(id.to_string())
+        .or_default()
+        .insert(hw_pos, shown);
+}
+
+/// Records that all the targets were cleared
+pub fn record_all_cleared(id: &str, hw_positions: impl IntoIterator<Item = u8>) {
+    let mut devices = SHOWN.lock().unwrap();
+    let targets = devices.entry(id.to_string()).or_default();
+
+    targets.clear();
+    targets.extend(
+        hw_positions
+            .into_iter()
+            .map(|hw_pos| (hw_pos, Shown::Cleared)),
+    );
+}
+
+/// Drops everything known about a device, so it starts fresh when reconnected
+pub fn forget_shown(id: &str) {
+    SHOWN.lock().unwrap().remove(id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn image(color: [u8; 3]) -> Shown {
+        Shown::image(&DynamicImage::ImageRgb8(RgbImage::from_pixel(
+            4,
+            4,
+            Rgb(color),
+        )))
+    }
+
+    #[test]
+    fn identical_pixels_are_skipped() {
+        record_shown("cache-same", 0, image([1, 2, 3]));
+
+        // A new image with the same pixels counts as already shown
+        assert!(is_shown("cache-same", 0, image([1, 2, 3])));
+        assert!(!is_shown("cache-same", 1, image([1, 2, 3])));
+        assert!(!is_shown("cache-other", 0, image([1, 2, 3])));
+    }
+
+    #[test]
+    fn changed_pixels_are_sent() {
+        record_shown("cache-changed", 0, image([1, 2, 3]));
+
+        assert!(!is_shown("cache-changed", 0, image([1, 2, 4])));
+        assert!(!is_shown("cache-changed", 0, Shown::Cleared));
+
+        // Same bytes in another shape are another image
+        let tall = Shown::image(&DynamicImage::ImageRgb8(RgbImage::from_pixel(
+            2,
+            8,
+            Rgb([1, 2, 3]),
+        )));
+        assert!(!is_shown("cache-changed", 0, tall));
+    }
+
+    #[test]
+    fn clearing_replaces_shown_images() {
+        record_shown("cache-clear", 0, image([1, 2, 3]));
+        record_shown("cache-clear", 5, image([1, 2, 3]));
+        record_all_cleared("cache-clear", [0, 1]);
+
+        assert!(!is_shown("cache-clear", 0, image([1, 2, 3])));
+        assert!(is_shown("cache-clear", 0, Shown::Cleared));
+        assert!(is_shown("cache-clear", 1, Shown::Cleared));
+        // Targets not cleared are unknown now
+        assert!(!is_shown("cache-clear", 5, image([1, 2, 3])));
+        assert!(!is_shown("cache-clear", 5, Shown::Cleared));
+    }
+
+    #[test]
+    fn reconnected_device_starts_unknown() {
+        record_shown("cache-reconnect", 0, image([1, 2, 3]));
+        record_shown("cache-kept", 0, image([1, 2, 3]));
+        forget_shown("cache-reconnect");
+
+        assert!(!is_shown("cache-reconnect", 0, image([1, 2, 3])));
+        assert!(is_shown("cache-kept", 0, image([1, 2, 3])));
+    }
+}
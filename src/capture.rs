@@ -0,0 +1,268 @@
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    sync::{LazyLock, Mutex},
+};
+use tokio::time::{Duration, Instant, sleep, sleep_until};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    TOKENS, TRACKER,
+    fake::{FakeDevice, fake_device, fake_device_info},
+    mappings::{CandidateDevice, Kind},
+    supervisor::supervisor_task,
+    watcher::device_info_to_candidate,
+};
+
+/// First line of every capture file
+const HEADER: &str = "# opendeck-n1 capture: <milliseconds> <vid>:<pid> <serial> <input> <state>";
+/// How long replay keeps going after the last input, so held keys and gestures can finish
+const REPLAY_TAIL: Duration = Duration::from_secs(2);
+
+/// File raw input is captured to, from `OPENDECK_AKP05_CAPTURE`
+static CAPTURE: LazyLock<Option<Mutex<File>>> = LazyLock::new(|| {
+    let path = env::var_os("OPENDECK_AKP05_CAPTURE").filter(|path| !path.is_empty())?;
+
+    let mut file = match File::create(&path) {
+        Ok(file) => file,
+        Err(err) => {
+            log::error!("Failed to create capture file {:?}: {}", path, err);
+            return None;
+        }
+    };
+
+    writeln!(file, "{}", HEADER).ok()?;
+    log::info!("Capturing raw input to {:?}", path);
+
+    Some(Mutex::new(file))
+});
+
+/// Capture timestamps count from the first captured input
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Appends raw input of a device to the capture file, if capturing
+pub fn capture_input(device: &CandidateDevice, input: u8, state: u8) {
+    let Some(capture) = CAPTURE.as_ref() else {
+        return;
+    };
+
+    let line = format_line(STARTED.elapsed(), device, input, state);
+
+    // Written line by line, so a capture survives the plugin crashing
+    if let Err(err) = writeln!(capture.lock().unwrap(), "{}", line) {
+        log::warn!("Failed to write capture: {}", err);
+    }
+}
+
+fn format_line(at: Duration, device: &CandidateDevice, input: u8, state: u8) -> String {
+    format!(
+        "{} {:04x}:{:04x} {} 0x{:02x} 0x{:02x}",
+        at.as_millis(),
+        device.dev.vendor_id,
+        device.dev.product_id,
+        device.dev.serial_number.as_deref().unwrap_or("-"),
+        input,
+        state
+    )
+}
+
+/// Raw input read back from a capture file
+#[derive(Debug)]
+struct Captured {
+    at: Duration,
+    vid: u16,
+    pid: u16,
+    serial: String,
+    input: u8,
+    state: u8,
+}
+
+fn parse_byte(raw: &str) -> Option<u8> {
+    match raw.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => raw.parse().ok(),
+    }
+}
+
+fn parse_line(line: &str) -> Option<Captured> {
+    let [at, vid_pid, serial, input, state] = line.split_whitespace().collect::<Vec<_>>()[..]
+    else {
+        return None;
+    };
+    let (vid, pid) = vid_pid.split_once(':')?;
+
+    Some(Captured {
+        at: Duration::from_millis(at.parse().ok()?),
+        vid: u16::from_str_radix(vid, 16).ok()?,
+        pid: u16::from_str_radix(pid, 16).ok()?,
+        serial: serial.to_string(),
+        input: parse_byte(input)?,
+        state: parse_byte(state)?,
+    })
+}
+
+fn read_capture(path: &Path) -> Result<Vec<Captured>, String> {
+    let contents = fs::read_to_string(path).map_err(|err| format!("failed to read: {}", err))?;
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            parse_line(line).ok_or_else(|| format!("line {} is not valid: {}", index + 1, line))
+        })
+        .collect()
+}
+
+/// Plays a capture back through fake devices with the captured timing, so the input goes
+/// through the same decoding, mapping and gestures as it did on the device it came from.
+/// Events that would go to OpenDeck are logged
+pub async fn replay(path: &Path) -> Result<(), String> {
+    let captured = read_capture(path)?;
+    log::info!(
+        "Replaying {} inputs from {}",
+        captured.len(),
+        path.display()
+    );
+
+    let tracker = TRACKER.lock().await.clone();
+    let mut devices: HashMap<&str, CancellationToken> = HashMap::new();
+
+    for entry in &captured {
+        if devices.contains_key(entry.serial.as_str()) {
+            continue;
+        }
+
+        let kind = Kind::from_vid_pid(entry.vid, entry.pid).ok_or_else(|| {
+            format!(
+                "no profile for {:04x}:{:04x} of {}",
+                entry.vid, entry.pid, entry.serial
+            )
+        })?;
+        let candidate = fake_device_info(&entry.serial, &kind)
+            .and_then(device_info_to_candidate)
+            .ok_or("fake devices are not supported on this platform")?;
+
+        let token = CancellationToken::new();
+        TOKENS
+            .write()
+            .await
+            .insert(candidate.id.clone(), token.clone());
+        tracker.spawn(supervisor_task::<FakeDevice>(candidate, token.clone()));
+
+        devices.insert(&entry.serial, token);
+    }
+
+    // Input sent once a device is connected is held until its reader starts
+    for serial in devices.keys() {
+        while fake_device(serial).is_none() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    let started = Instant::now();
+
+    for entry in &captured {
+        sleep_until(started + entry.at).await;
+
+        log::info!(
+            "REPLAY {} 0x{:02x} 0x{:02x}",
+            entry.serial,
+            entry.input,
+            entry.state
+        );
+        if let Some(device) = fake_device(&entry.serial) {
+            device.send_raw(entry.input, entry.state);
+        }
+    }
+
+    sleep(REPLAY_TAIL).await;
+
+    for token in devices.values() {
+        token.cancel();
+    }
+
+    tracker.close();
+    tracker.wait().await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappings::KINDS;
+
+    fn write_capture(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = env::temp_dir().join(format!(
+            "opendeck-n1-capture-{}-{}",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_back_captured_line() {
+        let kind = KINDS.first().unwrap();
+        let candidate = fake_device_info("captured", kind)
+            .and_then(device_info_to_candidate)
+            .unwrap();
+
+        let line = format_line(Duration::from_millis(1234), &candidate, 0x33, 0x01);
+        let captured = parse_line(&line).unwrap();
+
+        assert_eq!(captured.at, Duration::from_millis(1234));
+        assert_eq!((captured.vid, captured.pid), kind.vid_pid());
+        assert_eq!(captured.serial, "captured");
+        assert_eq!((captured.input, captured.state), (0x33, 0x01));
+    }
+
+    #[test]
+    fn parses_hex_and_decimal_bytes() {
+        let captured = parse_line("10 6603:1000 abc 0x1e 1").unwrap();
+        assert_eq!((captured.input, captured.state), (0x1e, 1));
+
+        let captured = parse_line("10 6603:1000 abc 30 0x00").unwrap();
+        assert_eq!((captured.input, captured.state), (0x1e, 0));
+
+        assert!(parse_line("10 6603:1000 abc 0x100 1").is_none());
+        assert!(parse_line("10 6603:1000 abc 0x01").is_none());
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let path = write_capture(
+            "comments",
+            &format!(
+                "{}\n\n0 6603:1000 abc 0x01 0x01\n  \n# released\n50 6603:1000 abc 0x01 0x00\n",
+                HEADER
+            ),
+        );
+        let captured = read_capture(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert_eq!(captured.len(), 2);
+        assert_eq!(captured[1].at, Duration::from_millis(50));
+        assert_eq!(captured[1].state, 0);
+    }
+
+    #[test]
+    fn reports_line_of_malformed_input() {
+        let path = write_capture(
+            "malformed",
+            &format!(
+                "{}\n0 6603:1000 abc 0x01 0x01\n\n50 6603 abc 0x01\n",
+                HEADER
+            ),
+        );
+        let err = read_capture(&path).unwrap_err();
+        fs::remove_file(&path).ok();
+
+        assert!(err.starts_with("line 4 "), "{}", err);
+    }
+}
@@ -0,0 +1,456 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use mirajazz::device::{Device, list_devices};
+use openaction::SetImageEvent;
+use std::{fs, path::Path};
+use tokio::{
+    signal::ctrl_c,
+    sync::mpsc::unbounded_channel,
+    time::{Duration, Instant, sleep},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    TOKENS, TRACKER,
+    capture::replay,
+    config::config,
+    device::handle_set_image,
+    mappings::{KINDS, QUERIES},
+    monitor::{InputEvent, set_monitor},
+    queue::{ImageCommand, image_queue},
+    shutdown,
+    supervisor::supervisor_task,
+    watcher::{get_candidates, id_to_serial},
+};
+
+pub const USAGE: &str = "\
+Usage: opendeck-n1 <command>
+
+Run without a command by OpenDeck. For debugging devices without OpenDeck, quit OpenDeck first,
+as only one program can talk to a device at a time:
+
+    list                             List connected devices and whether they can be opened
+    monitor [serial]                 Print input events of devices
+    test-pattern [serial]            Show numbered tiles on every key and LCD segment
+    set-image <position> <file> [serial]
+                                     Show an image at a UI position, as OpenDeck would
+    replay <file>                    Play back raw input captured with OPENDECK_AKP05_CAPTURE
+    help                             Show this message
+
+Devices stay connected until Ctrl-C, as disconnecting clears their screens.";
+
+/// How long to wait for devices to be ready
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Diagnostic command given on the command line
+#[derive(Debug)]
+pub enum Command<'a> {
+    Help,
+    List,
+    Monitor(Option<&'a str>),
+    TestPattern(Option<&'a str>),
+    SetImage {
+        position: u8,
+        path: &'a Path,
+        serial: Option<&'a str>,
+    },
+    Replay(&'a Path),
+}
+
+/// Parses command line arguments, `None` meaning the plugin was started by OpenDeck
+pub fn parse_args(args: &[String]) -> Result<Option<Command<'_>>, String> {
+    let args: Vec<&str> = args.iter().skip(1).map(String::as_str).collect();
+
+    let command = match args.as_slice() {
+        [] | ["help" | "-h" | "--help"] => Command::Help,
+        // OpenDeck passes -port, -pluginUUID and so on
+        [first, ..] if first.starts_with('-') => return Ok(None),
+        ["list"] => Command::List,
+        ["monitor", serial @ ..] if serial.len() <= 1 => Command::Monitor(serial.first().copied()),
+        ["test-pattern", serial @ ..] if serial.len() <= 1 => {
+            Command::TestPattern(serial.first().copied())
+        }
+        ["set-image", position, path, serial @ ..] if serial.len() <= 1 => Command::SetImage {
+            position: position
+                .parse()
+                .map_err(|_| format!("invalid position {:?}", position))?,
+            path: Path::new(*path),
+            serial: serial.first().copied(),
+        },
+        ["replay", path] => Command::Replay(Path::new(*path)),
+        [command, ..] => return Err(format!("unknown command or arguments: {}", command)),
+    };
+
+    Ok(Some(command))
+}
+
+pub async fn run(command: Command<'_>) -> Result<(), String> {
+    match command {
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Command::List => list().await,
+        Command::Monitor(serial) => {
+            // Input events only reach the monitor as long as nothing is connected to OpenDeck
+            let (sender, mut events) = unbounded_channel();
+            set_monitor(sender);
+            tokio::spawn(async move {
+                while let Some(event) = events.recv().await {
+                    match event {
+                        InputEvent::Key(id, event) => println!("{} key {:?}", id, event),
+                        InputEvent::Encoder(id, event) => println!("{} encoder {:?}", id, event),
+                    }
+                }
+            });
+
+            start_devices(serial).await?;
+            println!("Showing input events, press Ctrl-C to stop");
+            wait_and_stop().await
+        }
+        Command::TestPattern(serial) => {
+            for id in start_devices(serial).await? {
+                test_pattern(&id).await?;
+            }
+            println!("Showing test pattern, press Ctrl-C to stop");
+            wait_and_stop().await
+        }
+        Command::SetImage {
+            position,
+            path,
+            serial,
+        } => {
+            let image = image_data_url(path)?;
+
+            for id in start_devices(serial).await? {
+                let event = SetImageEvent {
+                    device: id.clone(),
+                    controller: None,
+                    position: Some(position),
+                    image: Some(image.clone()),
+                };
+
+                handle_set_image(event)
+                    .await
+                    .map_err(|err| format!("failed to set image on {}: {}", id, err))?;
+            }
+            println!("Showing {}, press Ctrl-C to stop", path.display());
+            wait_and_stop().await
+        }
+        Command::Replay(path) => replay(path).await,
+    }
+}
+
+/// Prints every device matching a known profile, with whether this user may open it
+async fn list() -> Result<(), String> {
+    let devices = list_devices(QUERIES.as_slice())
+        .await
+        .map_err(|err| format!("failed to list devices: {}", err))?;
+
+    if devices.is_empty() {
+        println!("No supported devices found");
+        return Ok(());
+    }
+
+    for dev in devices {
+        let name = KINDS
+            .iter()
+            .find(|kind| kind.vid_pid() == (dev.vendor_id, dev.product_id))
+            .map_or_else(|| dev.name.clone(), |kind| kind.human_name());
+
+        println!(
+            "{} ({:04x}:{:04x})\n    serial: {}\n    path:   {}\n    access: {}",
+            name,
+            dev.vendor_id,
+            dev.product_id,
+            dev.serial_number.as_deref().unwrap_or("<none>"),
+            device_path(&dev.id),
+            access(&dev.id)
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn device_path(id: &async_hid::DeviceId) -> String {
+    match id {
+        async_hid::DeviceId::DevPath(path) => path.display().to_string(),
+        _ => format!("{:?}", id),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn device_path(id: &async_hid::DeviceId) -> String {
+    format!("{:?}", id)
+}
+
+/// Tries opening the hidraw node for reading and writing, like connecting does
+#[cfg(target_os = "linux")]
+fn access(id: &async_hid::DeviceId) -> String {
+    let async_hid::DeviceId::DevPath(path) = id else {
+        return "unknown".to_string();
+    };
+
+    match fs::OpenOptions::new().read(true).write(true).open(path) {
+        Ok(_) => "ok".to_string(),
+        Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+            "permission denied, install the udev rules".to_string()
+        }
+        Err(err) => err.to_string(),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn access(_id: &async_hid::DeviceId) -> String {
+    "unknown".to_string()
+}
+
+/// Connects devices the way the plugin does and waits for them to be ready, returning their ids
+async fn start_devices(serial: Option<&str>) -> Result<Vec<String>, String> {
+    let candidates: Vec<_> = get_candidates()
+        .await
+        .map_err(|err| format!("failed to list devices: {}", err))?
+        .into_iter()
+        .filter(|candidate| serial.is_none_or(|serial| id_to_serial(&candidate.id) == Some(serial)))
+        .collect();
+
+    if candidates.is_empty() {
+        return Err("no matching device found".to_string());
+    }
+
+    let tracker = TRACKER.lock().await.clone();
+    let ids: Vec<String> = candidates
+        .iter()
+        .map(|candidate| candidate.id.clone())
+        .collect();
+
+    for candidate in candidates {
+        let token = CancellationToken::new();
+        TOKENS
+            .write()
+            .await
+            .insert(candidate.id.clone(), token.clone());
+        tracker.spawn(supervisor_task::<Device>(candidate, token));
+    }
+
+    let started = Instant::now();
+    for id in &ids {
+        while image_queue(id).await.is_none() {
+            if started.elapsed() > CONNECT_TIMEOUT {
+                return Err(format!("{} did not connect, see the log above", id));
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    Ok(ids)
+}
+
+async fn wait_and_stop() -> Result<(), String> {
+    ctrl_c()
+        .await
+        .map_err(|err| format!("failed to wait for Ctrl-C: {}", err))?;
+
+    shutdown().await;
+
+    let tracker = TRACKER.lock().await.clone();
+    tracker.close();
+    tracker.wait().await;
+
+    Ok(())
+}
+
+/// Reads an image file into a data url, like the ones OpenDeck sends
+fn image_data_url(path: &Path) -> Result<String, String> {
+    let mime = match ImageFormat::from_path(path) {
+        Ok(ImageFormat::Png) => "png",
+        Ok(ImageFormat::Jpeg) => "jpeg",
+        Ok(ImageFormat::Gif) => "gif",
+        Ok(ImageFormat::WebP) => "webp",
+        Ok(ImageFormat::Bmp) => "bmp",
+        _ => return Err(format!("unsupported image type of {}", path.display())),
+    };
+
+    let data =
+        fs::read(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+
+    Ok(format!(
+        "data:image/{};base64,{}",
+        mime,
+        STANDARD.encode(data)
+    ))
+}
+
+/// Shows every UI position with an image target as a coloured tile with its number
+async fn test_pattern(id: &str) -> Result<(), String> {
+    let Some((kind, queue)) = image_queue(id).await else {
+        return Err(format!("{} is not connected", id));
+    };
+    let settings = config().for_serial(id_to_serial(id));
+
+    let positions = (kind.row_count() * kind.col_count()) as u8;
+
+    for position in 0..positions {
+        let Some(Some(hw_pos)) = kind.image_position_to_hw(settings.remap_from_ui(position)) else {
+            continue;
+        };
+
+        let (width, height) = kind.image_size_for(hw_pos);
+        let tile = numbered_tile(position, width as u32, height as u32, positions);
+
+        queue
+            .send(ImageCommand::Set {
+                hw_pos,
+                format: kind.image_format_for(hw_pos),
+                image: DynamicImage::ImageRgb8(tile),
+            })
+            .map_err(|_| format!("{} disconnected", id))?;
+    }
+
+    Ok(())
+}
+
+/// Digits 0-9 as 3x5 bitmaps, one row per item with the leftmost pixel in the highest bit
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// Returns a tile with a colour along the hue wheel and the number written on it in white
+fn numbered_tile(number: u8, width: u32, height: u32, count: u8) -> RgbImage {
+    let mut tile = RgbImage::from_pixel(width, height, hue(number as f32 / count.max(1) as f32));
+
+    let digits: Vec<usize> = number
+        .to_string()
+        .bytes()
+        .map(|digit| (digit - b'0') as usize)
+        .collect();
+
+    // Every digit is 3 pixels wide with 1 pixel between them, scaled to cover half the tile
+    let columns = digits.len() as u32 * 4 - 1;
+    let scale = (width / 2 / columns).min(height / 2 / 5).max(1);
+    let mut text = RgbImage::new(columns * scale, 5 * scale);
+
+    for (index, digit) in digits.iter().enumerate() {
+        for (row, bits) in DIGITS[*digit].iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) == 0 {
+                    continue;
+                }
+
+                let x = (index as u32 * 4 + col) * scale;
+                let y = row as u32 * scale;
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        text.put_pixel(x + dx, y + dy, Rgb([255, 255, 255]));
+                    }
+                }
+            }
+        }
+    }
+
+    // Only the lit pixels are copied, so the tile colour stays around the digits
+    let (left, top) = (
+        (width.saturating_sub(text.width())) / 2,
+        (height.saturating_sub(text.height())) / 2,
+    );
+    for (x, y, pixel) in text.enumerate_pixels() {
+        if pixel[0] > 0 && left + x < width && top + y < height {
+            tile.put_pixel(left + x, top + y, *pixel);
+        }
+    }
+
+    tile
+}
+
+/// Returns a dark colour at a point along the hue wheel, from 0 to 1
+fn hue(at: f32) -> Rgb<u8> {
+    let sector = (at.fract() * 6.0) as u8;
+    let rise = ((at.fract() * 6.0).fract() * 160.0) as u8;
+    let fall = 160 - rise;
+
+    match sector {
+        0 => Rgb([160, rise, 0]),
+        1 => Rgb([fall, 160, 0]),
+        2 => Rgb([0, 160, rise]),
+        3 => Rgb([0, fall, 160]),
+        4 => Rgb([rise, 0, 160]),
+        _ => Rgb([160, 0, fall]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Command<'static>>, String> {
+        let args: Vec<String> = ["opendeck-n1"]
+            .iter()
+            .chain(args)
+            .map(|arg| arg.to_string())
+            .collect();
+
+        // Leaked so the parsed command can borrow from the arguments
+        parse_args(Vec::leak(args))
+    }
+
+    #[test]
+    fn leaves_opendeck_arguments_to_the_plugin() {
+        assert!(matches!(
+            parse(&["-port", "1234", "-pluginUUID", "uuid"]),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn shows_help_without_command() {
+        assert!(matches!(parse(&[]), Ok(Some(Command::Help))));
+        assert!(matches!(parse(&["help"]), Ok(Some(Command::Help))));
+        assert!(matches!(parse(&["--help"]), Ok(Some(Command::Help))));
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert!(matches!(parse(&["list"]), Ok(Some(Command::List))));
+        assert!(matches!(
+            parse(&["monitor"]),
+            Ok(Some(Command::Monitor(None)))
+        ));
+        assert!(matches!(
+            parse(&["monitor", "123"]),
+            Ok(Some(Command::Monitor(Some("123"))))
+        ));
+        assert!(matches!(
+            parse(&["set-image", "6", "tile.png"]),
+            Ok(Some(Command::SetImage {
+                position: 6,
+                serial: None,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_position() {
+        assert!(parse(&["set-image", "six", "tile.png"]).is_err());
+        assert!(parse(&["set-image", "256", "tile.png"]).is_err());
+    }
+
+    #[test]
+    fn rejects_extra_arguments() {
+        assert!(parse(&["list", "extra"]).is_err());
+        assert!(parse(&["monitor", "123", "456"]).is_err());
+        assert!(parse(&["set-image", "6", "tile.png", "123", "456"]).is_err());
+        assert!(parse(&["replay"]).is_err());
+        assert!(parse(&["unknown"]).is_err());
+    }
+}
@@ -0,0 +1,732 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, RwLock},
+    time::Duration,
+};
+
+use crate::{
+    encoders::{EncoderConfig, EncoderSettings},
+    gestures::{GestureConfig, GestureSettings},
+    images::{FitMode, ImageLayout, parse_color},
+    mappings::{KINDS, Kind, Rotation},
+    plugin_dir,
+};
+
+/// Name of the config file, looked up next to the plugin binary and then in the XDG config dir
+const CONFIG_FILE: &str = "config.toml";
+/// Directory inside the XDG config dir the config file lives in
+const XDG_DIR: &str = "opendeck-n1";
+
+const DEFAULT_BRIGHTNESS: u8 = 50;
+const DEFAULT_KEEPALIVE_SECS: u64 = 10;
+const DEFAULT_ANIMATION_FPS: u32 = 15;
+
+/// Settings from environment variables, read once on startup
+static ENV: LazyLock<EnvOverrides> = LazyLock::new(EnvOverrides::read);
+
+/// Settings set through environment variables, which take precedence over the config file
+#[derive(Debug, Default)]
+struct EnvOverrides {
+    /// `OPENDECK_AKP05_N1_MODE`
+    mode: Option<u8>,
+    /// `OPENDECK_AKP05_ANIMATION_FPS`
+    animation_fps: Option<u32>,
+    /// `OPENDECK_AKP05_LOG`
+    log_level: Option<String>,
+}
+
+impl EnvOverrides {
+    fn read() -> Self {
+        let animation_fps = env::var("OPENDECK_AKP05_ANIMATION_FPS")
+            .ok()
+            .and_then(|raw| match raw.trim().parse::<u32>() {
+                Ok(fps) if fps > 0 => Some(fps),
+                _ => {
+                    log::error!(
+                        "Invalid OPENDECK_AKP05_ANIMATION_FPS {:?}, ignoring it",
+                        raw
+                    );
+                    None
+                }
+            });
+
+        Self {
+            mode: env::var("OPENDECK_AKP05_N1_MODE")
+                .ok()
+                .and_then(|v| v.parse::<u8>().ok()),
+            animation_fps,
+            log_level: env::var("OPENDECK_AKP05_LOG").ok(),
+        }
+    }
+}
+
+/// Current config, swapped as a whole when the file changes
+static CONFIG: LazyLock<RwLock<Arc<Config>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(load_config())));
+
+/// Returns current config
+pub fn config() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
+}
+
+/// Plugin settings, with per-device overrides keyed by serial number.
+/// Environment variables take precedence over everything set here
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Config {
+    /// One of off, error, warn, info, debug, trace
+    pub log_level: Option<String>,
+    /// Mode the device is switched to on connect, overriding the profile
+    pub mode: Option<u8>,
+    /// Brightness set on connect, until OpenDeck sets its own
+    pub brightness: Option<u8>,
+    /// Seconds between keepalive packets, 0 disables them
+    pub keepalive_secs: Option<u64>,
+    /// Most animation frames per second sent to a device, shared by all its animations
+    pub animation_fps: Option<u32>,
+    /// Degrees the device is mounted turned clockwise, one of 0, 90, 180 or 270
+    pub orientation: Option<Rotation>,
+    /// Pairs of `[default position, new position]` moving keys along with their images
+    pub remap: Vec<(u8, u8)>,
+    pub layout: LayoutConfig,
+    pub gestures: GestureConfig,
+    pub encoders: EncoderConfig,
+    pub devices: HashMap<String, DeviceConfig>,
+}
+
+/// How images are laid out on the screens
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct LayoutConfig {
+    pub fit: Option<FitMode>,
+    /// UI position whose wide images are spread across the LCD segments
+    pub span: Option<u8>,
+    /// Colour transparent images are composited onto, as `#rrggbb`
+    pub background: Option<String>,
+    /// Colour of the bars around contained images, as `#rrggbb`
+    pub letterbox: Option<String>,
+}
+
+/// Overrides for a single device
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct DeviceConfig {
+    pub mode: Option<u8>,
+    pub brightness: Option<u8>,
+    pub keepalive_secs: Option<u64>,
+    pub animation_fps: Option<u32>,
+    pub orientation: Option<Rotation>,
+    /// Replaces the global remap table
+    pub remap: Option<Vec<(u8, u8)>>,
+    /// Replaces single layout settings, the others stay global
+    pub layout: LayoutConfig,
+    /// Replaces single gesture settings, keys replace the global keys as a whole
+    pub gestures: GestureConfig,
+    /// Replaces single encoder settings, the others stay global
+    pub encoders: EncoderConfig,
+}
+
+impl DeviceConfig {
+    const NONE: Self = Self {
+        mode: None,
+        brightness: None,
+        keepalive_secs: None,
+        animation_fps: None,
+        orientation: None,
+        remap: None,
+        layout: LayoutConfig {
+            fit: None,
+            span: None,
+            background: None,
+            letterbox: None,
+        },
+        gestures: GestureConfig {
+            long_press_ms: None,
+            double_press_ms: None,
+            repeat_delay_ms: None,
+            repeat_interval_ms: None,
+            virtual_rows: None,
+            keys: None,
+        },
+        encoders: EncoderConfig {
+            invert: None,
+            step: None,
+            coalesce_ms: None,
+            acceleration_threshold: None,
+            acceleration_max: None,
+        },
+    };
+}
+
+/// Settings of a device with overrides applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceSettings {
+    pub mode: Option<u8>,
+    pub brightness: u8,
+    /// `None` when keepalive packets are disabled
+    pub keepalive: Option<Duration>,
+    pub animation_fps: u32,
+    pub orientation: Rotation,
+    pub remap: Vec<(u8, u8)>,
+    pub layout: ImageLayout,
+    pub gestures: GestureSettings,
+    pub encoders: EncoderSettings,
+}
+
+impl DeviceSettings {
+    /// Returns UI position a key reports as, given the position it has in the default layout
+    pub fn remap_to_ui(&self, position: u8) -> u8 {
+        self.remap
+            .iter()
+            .find(|(from, _)| *from == position)
+            .map_or(position, |(_, to)| *to)
+    }
+
+    /// Returns position in the default layout an image for a UI position goes to
+    pub fn remap_from_ui(&self, position: u8) -> u8 {
+        self.remap
+            .iter()
+            .find(|(_, to)| *to == position)
+            .map_or(position, |(from, _)| *from)
+    }
+}
+
+impl Config {
+    /// Returns settings of a device, with its overrides and environment variables applied
+    pub fn for_serial(&self, serial: Option<&str>) -> DeviceSettings {
+        self.resolve(serial, &ENV)
+    }
+
+    fn resolve(&self, serial: Option<&str>, env: &EnvOverrides) -> DeviceSettings {
+        let overrides = serial
+            .and_then(|serial| self.devices.get(serial))
+            .unwrap_or(&DeviceConfig::NONE);
+
+        let keepalive_secs = overrides
+            .keepalive_secs
+            .or(self.keepalive_secs)
+            .unwrap_or(DEFAULT_KEEPALIVE_SECS);
+
+        DeviceSettings {
+            mode: env.mode.or(overrides.mode).or(self.mode),
+            brightness: overrides
+                .brightness
+                .or(self.brightness)
+                .unwrap_or(DEFAULT_BRIGHTNESS),
+            keepalive: (keepalive_secs > 0).then(|| Duration::from_secs(keepalive_secs)),
+            animation_fps: env
+                .animation_fps
+                .or(overrides.animation_fps)
+                .or(self.animation_fps)
+                .unwrap_or(DEFAULT_ANIMATION_FPS),
+            orientation: overrides
+                .orientation
+                .or(self.orientation)
+                .unwrap_or_default(),
+            remap: overrides.remap.as_ref().unwrap_or(&self.remap).clone(),
+            layout: ImageLayout::resolve(
+                overrides.layout.fit.or(self.layout.fit),
+                overrides.layout.span.or(self.layout.span),
+                overrides
+                    .layout
+                    .background
+                    .as_deref()
+                    .or(self.layout.background.as_deref()),
+                overrides
+                    .layout
+                    .letterbox
+                    .as_deref()
+                    .or(self.layout.letterbox.as_deref()),
+            ),
+            gestures: GestureSettings::resolve(&overrides.gestures, &self.gestures),
+            encoders: EncoderSettings::resolve(&overrides.encoders, &self.encoders),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(level) = &self.log_level
+            && parse_log_level(level).is_none()
+        {
+            return Err(format!("unknown log level {:?}", level));
+        }
+
+        let mut brightness = self
+            .devices
+            .values()
+            .filter_map(|device| device.brightness)
+            .chain(self.brightness);
+        if let Some(brightness) = brightness.find(|brightness| *brightness > 100) {
+            return Err(format!("brightness {} is above 100", brightness));
+        }
+
+        if self
+            .devices
+            .values()
+            .filter_map(|device| device.animation_fps)
+            .chain(self.animation_fps)
+            .any(|fps| fps == 0)
+        {
+            return Err("animation_fps has to be at least 1".to_string());
+        }
+
+        validate_remap(&self.remap).map_err(|err| format!("remap: {}", err))?;
+        for (serial, device) in &self.devices {
+            if let Some(remap) = &device.remap {
+                validate_remap(remap).map_err(|err| format!("remap of {}: {}", serial, err))?;
+            }
+        }
+
+        let layouts =
+            std::iter::once(&self.layout).chain(self.devices.values().map(|device| &device.layout));
+        for color in layouts
+            .flat_map(|layout| [&layout.background, &layout.letterbox])
+            .flatten()
+        {
+            if parse_color(color).is_none() {
+                return Err(format!("invalid colour {:?}, expected #rrggbb", color));
+            }
+        }
+
+        // Gesture targets have to be registered in the orientation each device is mounted in
+        let serials = std::iter::once(None).chain(self.devices.keys().map(Some));
+        for serial in serials {
+            let settings = self.resolve(serial.map(String::as_str), &EnvOverrides::default());
+            let context = serial.map_or_else(String::new, |serial| format!(" of {}", serial));
+
+            let kinds: Vec<Kind> = KINDS
+                .iter()
+                .map(|kind| kind.oriented(settings.orientation))
+                .collect();
+            settings
+                .gestures
+                .validate(&kinds)
+                .map_err(|err| format!("gestures{}: {}", context, err))?;
+            settings
+                .encoders
+                .validate()
+                .map_err(|err| format!("encoders{}: {}", context, err))?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns log level, with `OPENDECK_AKP05_LOG` taking precedence
+    pub fn log_level(&self) -> simplelog::LevelFilter {
+        self.resolve_log_level(&ENV)
+    }
+
+    fn resolve_log_level(&self, env: &EnvOverrides) -> simplelog::LevelFilter {
+        env.log_level
+            .as_deref()
+            .or(self.log_level.as_deref())
+            .and_then(parse_log_level)
+            .unwrap_or(simplelog::LevelFilter::Debug)
+    }
+}
+
+/// Checks that a remap table moves keys around without two keys ending up on one position.
+/// Every position a key is moved to has to be moved somewhere as well, so the table has to
+/// be a permutation, e.g. `[[6, 8], [8, 6]]` to swap two keys
+fn validate_remap(remap: &[(u8, u8)]) -> Result<(), String> {
+    // Positions have to exist on every supported device, as one table can apply to all of them
+    let grid = KINDS
+        .iter()
+        .map(|kind| kind.row_count() * kind.col_count())
+        .min()
+        .unwrap_or(0);
+
+    for (index, (from, to)) in remap.iter().enumerate() {
+        if let Some(position) = [from, to]
+            .into_iter()
+            .find(|position| **position as usize >= grid)
+        {
+            return Err(format!(
+                "position {} is outside of the {} key grid",
+                position, grid
+            ));
+        }
+
+        if remap[..index].iter().any(|(other, _)| other == from) {
+            return Err(format!("position {} is remapped twice", from));
+        }
+
+        if remap[..index].iter().any(|(_, other)| other == to) {
+            return Err(format!("more than one key is moved to position {}", to));
+        }
+    }
+
+    for (from, to) in remap {
+        if !remap.iter().any(|(other, _)| other == to) {
+            return Err(format!(
+                "key {} is moved to position {}, but the key already there is not moved away",
+                from, to
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn parse_log_level(raw: &str) -> Option<simplelog::LevelFilter> {
+    match raw.to_ascii_lowercase().as_str() {
+        "off" => Some(simplelog::LevelFilter::Off),
+        "error" => Some(simplelog::LevelFilter::Error),
+        "warn" | "warning" => Some(simplelog::LevelFilter::Warn),
+        "info" => Some(simplelog::LevelFilter::Info),
+        "debug" => Some(simplelog::LevelFilter::Debug),
+        "trace" => Some(simplelog::LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+/// Returns `$XDG_CONFIG_HOME`, falling back to `~/.config`
+fn xdg_config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+}
+
+/// Returns path of the config file in use, the one next to the plugin binary wins
+pub fn config_path() -> Option<PathBuf> {
+    let candidates = [
+        plugin_dir().map(|dir| dir.join(CONFIG_FILE)),
+        xdg_config_dir().map(|dir| dir.join(XDG_DIR).join(CONFIG_FILE)),
+    ];
+
+    candidates.into_iter().flatten().find(|path| path.is_file())
+}
+
+/// Reads and validates a config file
+fn read_config(path: &Path) -> Result<Config, String> {
+    let contents = fs::read_to_string(path).map_err(|err| format!("failed to read: {}", err))?;
+    let config = toml::from_str::<Config>(&contents).map_err(|err| err.to_string())?;
+    config.validate()?;
+
+    Ok(config)
+}
+
+fn load_config() -> Config {
+    let Some(path) = config_path() else {
+        log::debug!("No {} found, using defaults", CONFIG_FILE);
+        return Config::default();
+    };
+
+    match read_config(&path) {
+        Ok(config) => {
+            log::info!("Loaded config from {}", path.display());
+            config
+        }
+        Err(err) => {
+            log::error!("Invalid config {}: {}", path.display(), err);
+            Config::default()
+        }
+    }
+}
+
+/// Loads the config file again, keeping the current config if the file is invalid.
+/// Returns the previous and the new config if it was replaced
+pub fn reload_config() -> Option<(Arc<Config>, Arc<Config>)> {
+    let config = match config_path() {
+        Some(path) => match read_config(&path) {
+            Ok(config) => {
+                log::info!("Reloaded config from {}", path.display());
+                config
+            }
+            Err(err) => {
+                log::error!(
+                    "Invalid config {}, keeping the previous one: {}",
+                    path.display(),
+                    err
+                );
+                return None;
+            }
+        },
+        None => {
+            log::info!("Config file is gone, using defaults");
+            Config::default()
+        }
+    };
+
+    let config = Arc::new(config);
+    let previous = std::mem::replace(&mut *CONFIG.write().unwrap(), config.clone());
+
+    Some((previous, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn device_layout_overrides_single_settings() {
+        let config: Config = toml::from_str(
+            r##"
+            [layout]
+            fit = "contain"
+            span = 3
+            background = "#102030"
+
+            [devices.abc.layout]
+            fit = "cover"
+            letterbox = "#ffffff"
+            "##,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let global = config.for_serial(Some("other")).layout;
+        assert_eq!(global.fit, FitMode::Contain);
+        assert_eq!(global.span, Some(3));
+        // Letterbox follows the background unless set
+        assert_eq!(global.letterbox, Rgb([0x10, 0x20, 0x30]));
+
+        let device = config.for_serial(Some("abc")).layout;
+        assert_eq!(device.fit, FitMode::Cover);
+        assert_eq!(device.span, Some(3));
+        assert_eq!(device.background, Rgb([0x10, 0x20, 0x30]));
+        assert_eq!(device.letterbox, Rgb([0xff, 0xff, 0xff]));
+    }
+
+    #[test]
+    fn rejects_invalid_device_colour() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.abc.layout]
+            background = "red"
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn animation_fps_can_be_set_per_device() {
+        let config: Config = toml::from_str(
+            r#"
+            animation_fps = 30
+
+            [devices.abc]
+            animation_fps = 5
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(Config::default().for_serial(None).animation_fps, 15);
+        assert_eq!(config.for_serial(Some("other")).animation_fps, 30);
+        assert_eq!(config.for_serial(Some("abc")).animation_fps, 5);
+
+        let stopped: Config = toml::from_str("[devices.abc]\nanimation_fps = 0").unwrap();
+        assert!(stopped.validate().is_err());
+    }
+
+    #[test]
+    fn mode_and_brightness_can_be_set_per_device() {
+        let config: Config = toml::from_str(
+            r#"
+            mode = 2
+            brightness = 80
+
+            [devices.abc]
+            mode = 4
+
+            [devices.def]
+            brightness = 20
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let env = EnvOverrides::default();
+        let other = config.resolve(Some("other"), &env);
+        assert_eq!((other.mode, other.brightness), (Some(2), 80));
+        let abc = config.resolve(Some("abc"), &env);
+        assert_eq!((abc.mode, abc.brightness), (Some(4), 80));
+        let def = config.resolve(Some("def"), &env);
+        assert_eq!((def.mode, def.brightness), (Some(2), 20));
+
+        let defaults = Config::default().resolve(None, &env);
+        assert_eq!((defaults.mode, defaults.brightness), (None, 50));
+
+        let too_bright: Config = toml::from_str("[devices.abc]\nbrightness = 101").unwrap();
+        assert!(too_bright.validate().is_err());
+    }
+
+    #[test]
+    fn environment_wins_over_config() {
+        let config: Config = toml::from_str(
+            r#"
+            mode = 2
+            animation_fps = 30
+            log_level = "warn"
+
+            [devices.abc]
+            mode = 4
+            animation_fps = 5
+            "#,
+        )
+        .unwrap();
+
+        let env = EnvOverrides {
+            mode: Some(1),
+            animation_fps: Some(60),
+            log_level: Some("trace".to_string()),
+        };
+        for serial in [None, Some("abc")] {
+            let settings = config.resolve(serial, &env);
+            assert_eq!(settings.mode, Some(1));
+            assert_eq!(settings.animation_fps, 60);
+        }
+
+        // Unset variables leave the config alone
+        let env = EnvOverrides {
+            animation_fps: Some(60),
+            ..EnvOverrides::default()
+        };
+        assert_eq!(config.resolve(Some("abc"), &env).mode, Some(4));
+        assert_eq!(config.resolve_log_level(&env), simplelog::LevelFilter::Warn);
+
+        let env = EnvOverrides {
+            log_level: Some("trace".to_string()),
+            ..EnvOverrides::default()
+        };
+        assert_eq!(
+            config.resolve_log_level(&env),
+            simplelog::LevelFilter::Trace
+        );
+    }
+
+    #[test]
+    fn gestures_and_encoders_can_be_set_per_device() {
+        let config: Config = toml::from_str(
+            r#"
+            [gestures]
+            long_press_ms = 800
+            virtual_rows = 1
+
+            [[gestures.keys]]
+            position = 6
+            long_press = 21
+
+            [encoders]
+            step = 2
+
+            [devices.abc]
+            orientation = 90
+
+            [devices.abc.gestures]
+            long_press_ms = 300
+
+            [[devices.abc.gestures.keys]]
+            position = 7
+            long_press = 27
+
+            [devices.abc.encoders]
+            invert = true
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let other = config.for_serial(Some("other"));
+        assert_eq!(other.gestures.long_press_ms, 800);
+        assert_eq!(other.gestures.keys[0].position, 6);
+        assert!(!other.encoders.invert);
+        assert_eq!(other.encoders.step, 2);
+
+        let abc = config.for_serial(Some("abc"));
+        assert_eq!(abc.gestures.long_press_ms, 300);
+        assert_eq!(abc.gestures.virtual_rows, 1);
+        assert_eq!(abc.gestures.keys.len(), 1);
+        assert_eq!(abc.gestures.keys[0].position, 7);
+        assert!(abc.encoders.invert);
+        assert_eq!(abc.encoders.step, 2);
+
+        // Position 27 only exists in the virtual row of a sideways N1
+        let upright: Config = toml::from_str(
+            r#"
+            [gestures]
+            virtual_rows = 1
+
+            [[gestures.keys]]
+            position = 7
+            long_press = 27
+            "#,
+        )
+        .unwrap();
+        assert!(upright.validate().is_err());
+
+        let invalid: Config = toml::from_str("[devices.abc.encoders]\nstep = 0").unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_remaps_that_are_not_permutations() {
+        let invalid: [&[(u8, u8)]; 4] = [
+            // Key 7 would end up on top of key 8
+            &[(6, 8), (7, 6)],
+            &[(6, 7), (6, 8), (7, 6)],
+            &[(6, 8), (7, 8), (8, 6)],
+            &[(6, 7)],
+        ];
+        for remap in invalid {
+            assert!(validate_remap(remap).is_err(), "{:?} is valid", remap);
+        }
+
+        // An N1 has 21 keys and segments, so 21 is past the grid
+        assert!(validate_remap(&[(20, 21), (21, 20)]).is_err());
+        assert!(validate_remap(&[(255, 6), (6, 255)]).is_err());
+
+        let config: Config = toml::from_str("[devices.abc]\nremap = [[6, 7]]").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn accepts_identity_remap() {
+        let identity: Vec<(u8, u8)> = (0..21).map(|position| (position, position)).collect();
+        assert_eq!(validate_remap(&identity), Ok(()));
+        assert_eq!(validate_remap(&[]), Ok(()));
+
+        let settings = Config {
+            remap: identity,
+            ..Config::default()
+        }
+        .for_serial(None);
+        for position in 0..21 {
+            assert_eq!(settings.remap_to_ui(position), position);
+            assert_eq!(settings.remap_from_ui(position), position);
+        }
+    }
+
+    #[test]
+    fn remap_is_undone_for_images() {
+        let config: Config = toml::from_str(
+            r#"
+            # Rotates the first keypad row one key to the right
+            remap = [[6, 7], [7, 8], [8, 9], [9, 10], [10, 11], [11, 12], [12, 6], [0, 3], [3, 0]]
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let settings = config.for_serial(None);
+
+        assert_eq!(settings.remap_to_ui(6), 7);
+        assert_eq!(settings.remap_from_ui(7), 6);
+        for position in 0..=u8::MAX {
+            assert_eq!(
+                settings.remap_from_ui(settings.remap_to_ui(position)),
+                position
+            );
+            assert_eq!(
+                settings.remap_to_ui(settings.remap_from_ui(position)),
+                position
+            );
+        }
+    }
+}
@@ -1,38 +1,39 @@
-use data_url::DataUrl;
-use image::load_from_memory_with_format;
-use mirajazz::{device::Device, error::MirajazzError, state::DeviceStateUpdate};
+use mirajazz::{error::MirajazzError, state::DeviceStateUpdate};
 use openaction::{OUTBOUND_EVENT_MANAGER, SetImageEvent};
-use std::env;
-use std::sync::atomic::{AtomicBool, Ordering};
-use tokio::time::{Duration, sleep};
+use std::sync::Arc;
+use tokio::{
+    sync::mpsc::{self, UnboundedSender},
+    time::{Duration, sleep},
+};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    DEVICES, TOKENS,
+    DEVICES,
+    animations::{AnimationFrame, start_animation, stop_animation, stop_animations},
+    cache::{forget_shown, record_all_cleared},
+    config::{DeviceSettings, config},
+    encoders::{EncoderEvent, encoder_task},
+    errors::{ErrorClass, classify},
+    gestures::{KeyEvent, gesture_task, is_virtual_position},
+    hardware::Hardware,
+    images::{
+        Frame, ImageError, ImageLayout, decode_data_url, fit_image, run_image_job, slice_strip,
+    },
+    inputs::{INPUT_DEVICE, forget_device},
     mappings::{CandidateDevice, Kind},
+    queue::{
+        ImageCommand, forget_image_queue, image_queue, image_queue_task, register_image_queue,
+    },
+    restore::{last_brightness, record_image, record_images_cleared, restore_images},
+    supervisor::cancel_session,
+    watcher::id_to_serial,
 };
 
-const N1_UI_POS_TOP_LEFT: u8 = 0;
-const N1_UI_POS_TOP_MIDDLE: u8 = 1;
-const N1_UI_POS_TOP_RIGHT_ENCODER: u8 = 2;
-const N1_UI_POS_LCD_LEFT: u8 = 3;
-const N1_UI_POS_LCD_MIDDLE: u8 = 4;
-const N1_UI_POS_LCD_RIGHT: u8 = 5;
-const N1_UI_GRID_START: u8 = 6;
-const N1_UI_GRID_END: u8 = 20;
-
-const N1_LOGICAL_TOP_LEFT: u8 = 15;
-const N1_LOGICAL_TOP_RIGHT: u8 = 16;
-
-const N1_HW_KEY_START: u8 = 0;
-const N1_HW_KEY_END: u8 = 14;
-const N1_HW_SEGMENT_START: u8 = 15;
-const N1_HW_SEGMENT_END: u8 = 17;
-
-static N1_MAPPING_LOGGED: AtomicBool = AtomicBool::new(false);
-
 /// Initializes a device and listens for events
-pub async fn device_task(candidate: CandidateDevice, token: CancellationToken) {
+pub async fn device_task<H: Hardware + 'static>(
+    candidate: CandidateDevice,
+    token: CancellationToken,
+) {
     log::info!(
         "Running device task id={} kind={} vid=0x{:04x} pid=0x{:04x}",
         candidate.id,
@@ -41,15 +42,22 @@ pub async fn device_task(candidate: CandidateDevice, token: CancellationToken) {
         candidate.dev.product_id
     );
 
+    let settings = config().for_serial(candidate.dev.serial_number.as_deref());
+    log::debug!("Settings for {}: {:?}", candidate.id, settings);
+
+    // Everything past this point sees the device as mounted
+    let candidate = CandidateDevice {
+        kind: candidate.kind.oriented(settings.orientation),
+        ..candidate
+    };
+
     // Wrap in a closure so we can use `?` operator
-    let device = async || -> Result<Device, MirajazzError> {
-        let device = connect(&candidate).await?;
-
-        if matches!(candidate.kind, Kind::VsdInsideN1) {
-            let mode = env::var("OPENDECK_AKP05_N1_MODE")
-                .ok()
-                .and_then(|v| v.parse::<u8>().ok())
-                .unwrap_or(3);
+    let device = async || -> Result<H, MirajazzError> {
+        let device = connect::<H>(&candidate).await?;
+
+        let mode = settings.mode.or(candidate.kind.startup_mode());
+
+        if let Some(mode) = mode {
             log::info!(
                 "Setting device {} ({}) to startup mode {}",
                 candidate.id,
@@ -59,15 +67,18 @@ pub async fn device_task(candidate: CandidateDevice, token: CancellationToken) {
             device.set_mode(mode).await?;
         }
 
-        device.set_brightness(50).await?;
+        device
+            .set_brightness(last_brightness(&candidate.id).unwrap_or(settings.brightness))
+            .await?;
         device.clear_all_button_images().await?;
         device.flush().await?;
+        record_all_cleared(&candidate.id, candidate.kind.hw_image_targets());
 
         Ok(device)
     }()
     .await;
 
-    let device: Device = match device {
+    let device: H = match device {
         Ok(device) => device,
         Err(err) => {
             handle_error(&candidate.id, err).await;
@@ -81,13 +92,29 @@ pub async fn device_task(candidate: CandidateDevice, token: CancellationToken) {
         }
     };
 
+    log_mapping(&candidate.kind);
+
+    // Both have to be in place before OpenDeck starts sending images
+    DEVICES
+        .write()
+        .await
+        .insert(candidate.id.clone(), Arc::new(device));
+    let image_commands = register_image_queue(&candidate).await;
+
+    // Done before registering, so images OpenDeck sends afterwards win over the restored ones
+    restore_images(&candidate.id).await;
+
+    // Taken from the settings the session started with, as OpenDeck only learns the grid size
+    // when registering
+    let rows = candidate.kind.row_count() + settings.gestures.virtual_rows;
+
     log::info!("Registering device {}", candidate.id);
     if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
         log::debug!(
             "register_device id={} name={} rows={} cols={} encoders={} type={}",
             candidate.id,
             candidate.kind.human_name(),
-            candidate.kind.row_count(),
+            rows,
             candidate.kind.col_count(),
             candidate.kind.encoder_count(),
             candidate.kind.device_type()
@@ -96,7 +123,7 @@ pub async fn device_task(candidate: CandidateDevice, token: CancellationToken) {
             .register_device(
                 candidate.id.clone(),
                 candidate.kind.human_name(),
-                candidate.kind.row_count() as u8,
+                rows as u8,
                 candidate.kind.col_count() as u8,
                 candidate.kind.encoder_count() as u8,
                 candidate.kind.device_type(),
@@ -105,32 +132,49 @@ pub async fn device_task(candidate: CandidateDevice, token: CancellationToken) {
             .unwrap();
     }
 
-    DEVICES.write().await.insert(candidate.id.clone(), device);
+    let (key_events, key_events_rx) = mpsc::unbounded_channel();
+    let (encoder_events, encoder_events_rx) = mpsc::unbounded_channel();
 
     tokio::select! {
-        _ = device_events_task(&candidate) => {},
+        _ = device_events_task(&candidate, key_events, encoder_events) => {},
+        _ = gesture_task(&candidate, key_events_rx) => {},
+        _ = encoder_task(&candidate, encoder_events_rx) => {},
+        _ = image_queue_task(&candidate, image_commands) => {},
         _ = keepalive_task(&candidate) => {},
         _ = token.cancelled() => {}
     };
 
     log::info!("Shutting down device {:?}", candidate);
 
-    if let Some(device) = DEVICES.read().await.get(&candidate.id) {
+    if let Some(device) = DEVICES.write().await.remove(&candidate.id) {
         device.shutdown().await.ok();
     }
 
+    stop_animations(&candidate.id);
+    forget_image_queue(&candidate.id).await;
+    forget_device(&candidate.id);
+    forget_shown(&candidate.id);
+
     log::info!("Device task finished for {:?}", candidate);
 }
 
 /// Sends periodic keepalive packets to reduce idle-time disconnects on some devices.
 async fn keepalive_task(candidate: &CandidateDevice) -> Result<(), MirajazzError> {
-    const KEEPALIVE_INTERVAL_SECS: u64 = 10;
+    // How often to check whether disabled keepalive got enabled by a config reload
+    const DISABLED_RECHECK_SECS: u64 = 5;
 
     loop {
-        sleep(Duration::from_secs(KEEPALIVE_INTERVAL_SECS)).await;
+        // Looked up every time, so config reloads apply without reconnecting
+        let settings = config().for_serial(candidate.dev.serial_number.as_deref());
+        let Some(interval) = settings.keepalive else {
+            sleep(Duration::from_secs(DISABLED_RECHECK_SECS)).await;
+            continue;
+        };
+
+        sleep(interval).await;
 
-        let devices = DEVICES.read().await;
-        let Some(device) = devices.get(&candidate.id) else {
+        let device = DEVICES.read().await.get(&candidate.id).cloned();
+        let Some(device) = device else {
             log::debug!(
                 "Keepalive task stopped, device {} is not in map",
                 candidate.id
@@ -139,7 +183,6 @@ async fn keepalive_task(candidate: &CandidateDevice) -> Result<(), MirajazzError
         };
 
         if let Err(e) = device.keep_alive().await {
-            drop(devices);
             log::warn!("Keepalive packet failed for {}: {}", candidate.id, e);
             if !handle_error(&candidate.id, e).await {
                 return Ok(());
@@ -165,9 +208,7 @@ pub async fn handle_error(id: &String, err: MirajazzError) -> bool {
     }
 
     log::info!("Cancelling tasks for device {}", id);
-    if let Some(token) = TOKENS.read().await.get(id) {
-        token.cancel();
-    }
+    cancel_session(id).await;
 
     log::info!("Removing device {} from the list", id);
     DEVICES.write().await.remove(id);
@@ -177,8 +218,10 @@ pub async fn handle_error(id: &String, err: MirajazzError) -> bool {
     false
 }
 
-pub async fn connect(candidate: &CandidateDevice) -> Result<Device, MirajazzError> {
+pub async fn connect<H: Hardware>(candidate: &CandidateDevice) -> Result<H, MirajazzError> {
     const MAX_CONNECT_ATTEMPTS: u8 = 10;
+    // udev applies permissions a moment after the device node shows up, so give it a short while
+    const MAX_PERMISSION_ATTEMPTS: u8 = 3;
     const RETRY_DELAY_MS: u64 = 300;
 
     log::debug!(
@@ -189,7 +232,7 @@ pub async fn connect(candidate: &CandidateDevice) -> Result<Device, MirajazzErro
         candidate.kind.encoder_count()
     );
     for attempt in 1..=MAX_CONNECT_ATTEMPTS {
-        let result = Device::connect(
+        let result = H::connect(
             &candidate.dev,
             candidate.kind.protocol_version(),
             candidate.kind.key_count(),
@@ -199,36 +242,51 @@ pub async fn connect(candidate: &CandidateDevice) -> Result<Device, MirajazzErro
 
         match result {
             Ok(device) => {
+                let (vid, pid) = device.vid_pid();
                 log::info!(
                     "Connected id={} (runtime vid=0x{:04x} pid=0x{:04x}) after attempt {}/{}",
                     candidate.id,
-                    device.vid,
-                    device.pid,
+                    vid,
+                    pid,
                     attempt,
                     MAX_CONNECT_ATTEMPTS
                 );
                 return Ok(device);
             }
             Err(e) => {
-                let msg = e.to_string();
-                let retryable = msg.contains("Permission denied")
-                    || msg.contains("Resource busy")
-                    || msg.contains("Disconnected");
+                let class = classify(&e);
+                let retryable = match class {
+                    ErrorClass::Retryable => attempt < MAX_CONNECT_ATTEMPTS,
+                    ErrorClass::Permission => attempt < MAX_PERMISSION_ATTEMPTS,
+                    ErrorClass::Fatal => false,
+                };
 
-                if retryable && attempt < MAX_CONNECT_ATTEMPTS {
+                if retryable {
                     log::warn!(
-                        "Connect attempt {}/{} failed for {}: {}. Retrying in {}ms",
+                        "Connect attempt {}/{} failed for {} ({:?}): {}. Retrying in {}ms",
                         attempt,
                         MAX_CONNECT_ATTEMPTS,
                         candidate.id,
-                        msg,
+                        class,
+                        e,
                         RETRY_DELAY_MS
                     );
                     sleep(Duration::from_millis(RETRY_DELAY_MS)).await;
                     continue;
                 }
 
-                log::error!("Error while connecting to device: {e}");
+                if class == ErrorClass::Permission {
+                    log::error!(
+                        "No permission to open {} ({:04x}:{:04x}). Install the udev rules from \
+                         40-opendeck-vsd-n1.rules into /etc/udev/rules.d/, run \
+                         `sudo udevadm control --reload-rules` and replug the device",
+                        candidate.kind.human_name(),
+                        candidate.dev.vendor_id,
+                        candidate.dev.product_id
+                    );
+                }
+
+                log::error!("Error while connecting to device ({:?}): {e}", class);
                 return Err(e);
             }
         }
@@ -238,12 +296,16 @@ pub async fn connect(candidate: &CandidateDevice) -> Result<Device, MirajazzErro
 }
 
 /// Handles events from device to OpenDeck
-async fn device_events_task(candidate: &CandidateDevice) -> Result<(), MirajazzError> {
+async fn device_events_task(
+    candidate: &CandidateDevice,
+    key_events: UnboundedSender<KeyEvent>,
+    encoder_events: UnboundedSender<EncoderEvent>,
+) -> Result<(), MirajazzError> {
     log::info!("Connecting to {} for incoming events", candidate.id);
 
     let devices_lock = DEVICES.read().await;
     let reader = match devices_lock.get(&candidate.id) {
-        Some(device) => device.get_reader(crate::inputs::process_input_n1),
+        Some(device) => device.reader(crate::inputs::process_input_n1),
         None => return Ok(()),
     };
     drop(devices_lock);
@@ -255,7 +317,10 @@ async fn device_events_task(candidate: &CandidateDevice) -> Result<(), MirajazzE
     loop {
         log::debug!("Reading updates...");
 
-        let updates = match reader.read(None).await {
+        let updates = match INPUT_DEVICE
+            .scope(candidate.clone(), reader.read(None))
+            .await
+        {
             Ok(updates) => updates,
             Err(e) => {
                 if !handle_error(&candidate.id, e).await {
@@ -267,73 +332,65 @@ async fn device_events_task(candidate: &CandidateDevice) -> Result<(), MirajazzE
         };
 
         for update in updates {
-            log_n1_mapping_once();
             log::debug!("New update: {:#?}", update);
 
             let id = candidate.id.clone();
 
-            if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-                match update {
-                    DeviceStateUpdate::ButtonDown(key) => {
-                        match map_input_key_to_ui(key) {
-                            Some(mapped) => {
-                                log::info!(
-                                    "EVENT device={} ButtonDown key={} mapped_key={}",
-                                    id,
-                                    key,
-                                    mapped
-                                );
-                                outbound.key_down(id, mapped).await.unwrap();
-                            }
-                            None => {
-                                log::debug!(
-                                    "Ignoring unmapped input key={} for {}",
-                                    key,
-                                    candidate.kind.human_name()
-                                );
-                            }
-                        }
-                    }
-                    DeviceStateUpdate::ButtonUp(key) => {
-                        match map_input_key_to_ui(key) {
-                            Some(mapped) => {
-                                log::info!(
-                                    "EVENT device={} ButtonUp key={} mapped_key={}",
-                                    id,
-                                    key,
-                                    mapped
-                                );
-                                outbound.key_up(id, mapped).await.unwrap();
-                            }
-                            None => {
-                                log::debug!(
-                                    "Ignoring unmapped input key={} for {}",
-                                    key,
-                                    candidate.kind.human_name()
-                                );
-                            }
-                        }
-                    }
-                    DeviceStateUpdate::EncoderDown(encoder) => {
-                        log::info!("EVENT device={} EncoderDown encoder={}", id, encoder);
-                        outbound.encoder_down(id, encoder).await.unwrap();
+            match update {
+                DeviceStateUpdate::ButtonDown(key) => match map_input_key_to_ui(candidate, key) {
+                    Some(mapped) => {
+                        log::info!(
+                            "EVENT device={} ButtonDown key={} mapped_key={}",
+                            id,
+                            key,
+                            mapped
+                        );
+                        key_events.send(KeyEvent::Down(mapped)).ok();
                     }
-                    DeviceStateUpdate::EncoderUp(encoder) => {
-                        log::info!("EVENT device={} EncoderUp encoder={}", id, encoder);
-                        outbound.encoder_up(id, encoder).await.unwrap();
+                    None => {
+                        log::debug!(
+                            "Ignoring unmapped input key={} for {}",
+                            key,
+                            candidate.kind.human_name()
+                        );
                     }
-                    DeviceStateUpdate::EncoderTwist(encoder, val) => {
+                },
+                DeviceStateUpdate::ButtonUp(key) => match map_input_key_to_ui(candidate, key) {
+                    Some(mapped) => {
                         log::info!(
-                            "EVENT device={} EncoderTwist encoder={} delta={}",
+                            "EVENT device={} ButtonUp key={} mapped_key={}",
                             id,
-                            encoder,
-                            val
+                            key,
+                            mapped
                         );
-                        outbound
-                            .encoder_change(id, encoder, val as i16)
-                            .await
-                            .unwrap();
+                        key_events.send(KeyEvent::Up(mapped)).ok();
                     }
+                    None => {
+                        log::debug!(
+                            "Ignoring unmapped input key={} for {}",
+                            key,
+                            candidate.kind.human_name()
+                        );
+                    }
+                },
+                DeviceStateUpdate::EncoderDown(encoder) => {
+                    log::info!("EVENT device={} EncoderDown encoder={}", id, encoder);
+                    encoder_events.send(EncoderEvent::Down(encoder)).ok();
+                }
+                DeviceStateUpdate::EncoderUp(encoder) => {
+                    log::info!("EVENT device={} EncoderUp encoder={}", id, encoder);
+                    encoder_events.send(EncoderEvent::Up(encoder)).ok();
+                }
+                DeviceStateUpdate::EncoderTwist(encoder, val) => {
+                    log::info!(
+                        "EVENT device={} EncoderTwist encoder={} delta={}",
+                        id,
+                        encoder,
+                        val
+                    );
+                    encoder_events
+                        .send(EncoderEvent::Twist(encoder, val as i16))
+                        .ok();
                 }
             }
         }
@@ -342,116 +399,172 @@ async fn device_events_task(candidate: &CandidateDevice) -> Result<(), MirajazzE
     Ok(())
 }
 
-fn log_n1_mapping_once() {
-    if N1_MAPPING_LOGGED.swap(true, Ordering::Relaxed) {
-        return;
-    }
+/// Logs how UI positions map to the hardware, handy when adding new profiles
+fn log_mapping(kind: &Kind) {
+    let profile = kind.profile();
 
-    log::info!("N1 mapping self-test (one-time)");
-    log::info!(
-        "N1 UI top row: top-left key={}, top-middle key={}, top-right encoder={}",
-        N1_UI_POS_TOP_LEFT,
-        N1_UI_POS_TOP_MIDDLE,
-        N1_UI_POS_TOP_RIGHT_ENCODER
-    );
-    log::info!(
-        "N1 UI LCD row (button targets): left={}, middle={}, right={}",
-        N1_UI_POS_LCD_LEFT,
-        N1_UI_POS_LCD_MIDDLE,
-        N1_UI_POS_LCD_RIGHT
-    );
-    log::info!(
-        "N1 input mapping: hw_top_left={} -> ui_key={}, hw_top_middle={} -> ui_key={}",
-        N1_LOGICAL_TOP_LEFT,
-        N1_UI_POS_TOP_LEFT,
-        N1_LOGICAL_TOP_RIGHT,
-        N1_UI_POS_TOP_MIDDLE
-    );
     log::info!(
-        "N1 top buttons are input-only: logical keys {} and {} do not support images",
-        N1_LOGICAL_TOP_LEFT,
-        N1_LOGICAL_TOP_RIGHT
-    );
-    log::info!(
-        "N1 input mapping: hw_keypad {}..{} -> ui_key {}..{} (offset +{})",
-        N1_HW_KEY_START,
-        N1_HW_KEY_END,
-        N1_UI_GRID_START,
-        N1_UI_GRID_END,
-        N1_UI_GRID_START
-    );
-    log::info!(
-        "N1 image mapping (Keypad): ui_key {}..{} -> hw_button {}..{}",
-        N1_UI_GRID_START,
-        N1_UI_GRID_END,
-        N1_HW_KEY_START,
-        N1_HW_KEY_END
-    );
-    log::info!(
-        "N1 image mapping (LCD row): ui_key {}..{} -> hw_button {}..{}",
-        N1_UI_POS_LCD_LEFT,
-        N1_UI_POS_LCD_RIGHT,
-        N1_HW_SEGMENT_START,
-        N1_HW_SEGMENT_END
+        "{} mapping self-test: grid {}x{} ({:?}), {} keys, {} encoders",
+        kind.human_name(),
+        kind.row_count(),
+        kind.col_count(),
+        kind.orientation(),
+        kind.key_count(),
+        kind.encoder_count()
     );
+    for (key, code) in profile.inputs.keys.iter().enumerate() {
+        log::info!(
+            "{} input mapping: raw=0x{:02x} -> logical={} -> ui_key={}",
+            kind.human_name(),
+            code,
+            key,
+            kind.input_key_to_ui(key as u8).unwrap_or_default()
+        );
+    }
+    let grid = (kind.row_count() * kind.col_count()) as u8;
+    for (ui, hw) in (0..grid).filter_map(|ui| Some((ui, kind.image_position_to_hw(ui)??))) {
+        log::info!(
+            "{} image mapping: ui_key={} -> hw_button={} ({})",
+            kind.human_name(),
+            ui,
+            hw,
+            if profile.layout.segment_targets.contains(&hw) {
+                "segment"
+            } else {
+                "key"
+            }
+        );
+    }
 }
 
-fn map_input_key_to_ui(key: u8) -> Option<u8> {
-    match key {
-        0..=14 => Some(key + N1_UI_GRID_START),
-        N1_LOGICAL_TOP_LEFT => Some(N1_UI_POS_TOP_LEFT),
-        N1_LOGICAL_TOP_RIGHT => Some(N1_UI_POS_TOP_MIDDLE),
-        _ => None,
+/// Returns UI position of a key, with the configured remap applied
+fn map_input_key_to_ui(candidate: &CandidateDevice, key: u8) -> Option<u8> {
+    let position = candidate.kind.input_key_to_ui(key)?;
+    let settings = config().for_serial(candidate.dev.serial_number.as_deref());
+
+    Some(settings.remap_to_ui(position))
+}
+
+fn map_key_image_position_to_hw(
+    kind: &Kind,
+    settings: &DeviceSettings,
+    position: u8,
+) -> Result<Option<u8>, MirajazzError> {
+    // Virtual rows only exist to bind gestures to, so they have nowhere to show images
+    if is_virtual_position(kind, &settings.gestures, position) {
+        return Ok(None);
     }
+
+    kind.image_position_to_hw(settings.remap_from_ui(position))
+        .ok_or(MirajazzError::BadData)
 }
 
-fn map_key_image_position_to_hw(position: u8) -> Result<Option<u8>, MirajazzError> {
-    let mapped = match position {
-        // Top row: two input-only keys + encoder spot.
-        N1_UI_POS_TOP_LEFT | N1_UI_POS_TOP_MIDDLE | N1_UI_POS_TOP_RIGHT_ENCODER => return Ok(None),
-        // LCD row: left/middle/right segment targets.
-        N1_UI_POS_LCD_LEFT => return Ok(Some(15)),
-        N1_UI_POS_LCD_MIDDLE => return Ok(Some(16)),
-        N1_UI_POS_LCD_RIGHT => return Ok(Some(17)),
-        // Keypad rows in UI (positions 6..20) map to hardware image keys 0..14.
-        N1_UI_GRID_START..=N1_UI_GRID_END => position - N1_UI_GRID_START,
-        _ => return Err(MirajazzError::BadData),
-    };
+/// With spanning enabled the screen segments belong to the span position,
+/// so other positions mapped to segments are left alone
+fn is_spanned_over(kind: &Kind, layout: &ImageLayout, position: u8, hw_positions: &[u8]) -> bool {
+    layout.span.is_some_and(|span| span != position)
+        && hw_positions
+            .iter()
+            .any(|hw_pos| kind.segment_targets().contains(hw_pos))
+}
+
+/// Decodes an image and fits every frame of it to the given hardware positions, or slices
+/// them across the LCD segments when spanning. Meant to run on the blocking pool, see [`run_image_job`]
+fn prepare_frames(
+    kind: &Kind,
+    layout: &ImageLayout,
+    position: u8,
+    positions: Vec<u8>,
+    data: &str,
+) -> Result<Vec<AnimationFrame>, ImageError> {
+    let mut frames = Vec::new();
+    let mut span = None;
+
+    for frame in decode_data_url(data, layout.background)? {
+        let Frame { image, delay } = frame?;
+
+        // Frames of an animation share the canvas size, so the first one decides
+        let span = *span.get_or_insert_with(|| {
+            let elongated = if kind.segments_vertical() {
+                image.height() > image.width()
+            } else {
+                image.width() > image.height()
+            };
+
+            layout.span == Some(position) && elongated && !kind.segment_targets().is_empty()
+        });
+
+        let uploads = if span {
+            let segments = kind.segment_targets();
+            log::debug!(
+                "Spanning {}x{} image at position={} across segments {:?}",
+                image.width(),
+                image.height(),
+                position,
+                segments
+            );
 
-    if (N1_HW_KEY_START..=N1_HW_KEY_END).contains(&mapped) {
-        Ok(Some(mapped))
-    } else {
-        Err(MirajazzError::BadData)
+            let tiles = slice_strip(
+                &image,
+                kind.image_size_for(segments[0]),
+                segments.len(),
+                kind.segments_vertical(),
+                layout,
+            );
+            segments.iter().copied().zip(tiles).collect()
+        } else {
+            positions
+                .iter()
+                .map(|hw_pos| {
+                    let size = kind.image_size_for(*hw_pos);
+                    (*hw_pos, fit_image(&image, size, layout))
+                })
+                .collect()
+        };
+
+        frames.push(AnimationFrame { uploads, delay });
     }
+
+    Ok(frames)
 }
 
 /// Handles different combinations of "set image" event, including clearing the specific buttons and whole device
-pub async fn handle_set_image(device: &Device, evt: SetImageEvent) -> Result<(), MirajazzError> {
+pub async fn handle_set_image(evt: SetImageEvent) -> Result<(), MirajazzError> {
     let is_encoder = evt.controller.as_deref() == Some("Encoder");
-    let kind = Kind::VsdInsideN1;
+    let Some((kind, queue)) = image_queue(&evt.device).await else {
+        log::error!("Received event for unknown device: {}", evt.device);
+        return Ok(());
+    };
+    let settings = config().for_serial(id_to_serial(&evt.device));
 
     log::debug!(
-        "SetImage request device(vid=0x{:04x},pid=0x{:04x},kind={}) position={:?} controller={:?} has_image={}",
-        device.vid,
-        device.pid,
+        "SetImage request device(id={},kind={}) position={:?} controller={:?} has_image={}",
+        evt.device,
         kind.human_name(),
         evt.position,
         evt.controller,
         evt.image.is_some()
     );
 
+    // Remembered to be restored once the device reconnects, images only once they decoded
+    match (evt.position, &evt.image) {
+        (Some(position), None) if !is_encoder => record_image(&evt.device, position, None),
+        (None, None) => record_images_cleared(&evt.device),
+        _ => {}
+    }
+
     match (evt.position, evt.image) {
         (Some(position), Some(image)) => {
             if is_encoder {
                 log::debug!(
-                    "Ignoring encoder image set at position={} (encoder is input-only on N1)",
+                    "Ignoring encoder image set at position={} (encoders are input-only)",
                     position
                 );
                 return Ok(());
             }
 
             log::debug!("Setting image for requested position {}", position);
-            let mapped = map_key_image_position_to_hw(position)?.map(|v| vec![v]);
+            let mapped = map_key_image_position_to_hw(&kind, &settings, position)?.map(|v| vec![v]);
 
             let Some(positions) = mapped else {
                 log::debug!(
@@ -459,45 +572,102 @@ pub async fn handle_set_image(device: &Device, evt: SetImageEvent) -> Result<(),
                     position,
                     evt.controller
                 );
+                // A remap can give the position a screen later, the image is checked then
+                record_image(&evt.device, position, Some(&image));
                 return Ok(());
             };
-            log::debug!("Mapped image positions={:?} (is_encoder={}) for kind={}", positions, is_encoder, kind.human_name());
 
-            // OpenDeck sends image as a data url, so parse it using a library
-            let url = DataUrl::process(image.as_str()).unwrap(); // Isn't expected to fail, so unwrap it is
-            let (body, _fragment) = url.decode_to_vec().unwrap(); // Same here
+            if is_spanned_over(&kind, &settings.layout, position, &positions) {
+                log::debug!(
+                    "Ignoring image set for position={} covered by span",
+                    position
+                );
+                record_image(&evt.device, position, Some(&image));
+                return Ok(());
+            }
+
+            log::debug!(
+                "Mapped image positions={:?} (is_encoder={}) for kind={}",
+                positions,
+                is_encoder,
+                kind.human_name()
+            );
+
+            // Whatever was playing here is replaced, even if the new image turns out broken
+            stop_animation(&evt.device, position);
+
+            let frames = {
+                let (kind, layout, image) = (kind.clone(), settings.layout, image.clone());
+                run_image_job(move || prepare_frames(&kind, &layout, position, positions, &image))
+                    .await
+            };
 
-            // Allow only image/jpeg mime for now
-            if url.mime_type().subtype != "jpeg" {
-                log::error!("Incorrect mime type: {}", url.mime_type());
+            let mut frames = match frames {
+                Ok(frames) => {
+                    record_image(&evt.device, position, Some(&image));
+                    frames
+                }
+                Err(err) => {
+                    // A broken image is not worth dropping the device over, enough to just log it.
+                    // It is not restored either, as it would only fail again on every reconnect
+                    log::error!(
+                        "Can't show image at position={} on {}: {}",
+                        position,
+                        kind.human_name(),
+                        err
+                    );
+                    record_image(&evt.device, position, None);
+                    return Ok(());
+                }
+            };
 
-                return Ok(()); // Not a fatal error, enough to just log it
+            // A narrow image at the span position only covers its own segment,
+            // the others would keep showing tiles of an earlier wide image
+            if settings.layout.span == Some(position)
+                && let Some(frame) = frames.first()
+            {
+                for hw_pos in kind.segment_targets() {
+                    if !frame
+                        .uploads
+                        .iter()
+                        .any(|(uploaded, _)| *uploaded == hw_pos)
+                    {
+                        queue.send(ImageCommand::Clear(hw_pos)).ok();
+                    }
+                }
             }
 
-            let image = load_from_memory_with_format(body.as_slice(), image::ImageFormat::Jpeg)?;
+            if frames.len() > 1 {
+                start_animation(&evt.device, position, kind, queue, frames).await;
+                return Ok(());
+            }
 
-            for hw_pos in positions {
-                let image_format = if (N1_HW_SEGMENT_START..=N1_HW_SEGMENT_END).contains(&hw_pos) {
-                    kind.touch_image_format()
-                } else {
-                    kind.image_format()
-                };
-                device
-                    .set_button_image(hw_pos, image_format, image.clone())
-                    .await?;
+            let Some(frame) = frames.pop() else {
+                return Ok(());
+            };
+
+            for (hw_pos, image) in frame.uploads {
+                queue
+                    .send(ImageCommand::Set {
+                        hw_pos,
+                        format: kind.image_format_for(hw_pos),
+                        image,
+                    })
+                    .ok();
             }
-            device.flush().await?;
         }
         (Some(position), None) => {
             if is_encoder {
                 log::debug!(
-                    "Ignoring encoder image clear at position={} (encoder is input-only on N1)",
+                    "Ignoring encoder image clear at position={} (encoders are input-only)",
                     position
                 );
                 return Ok(());
             }
 
-            let mapped = map_key_image_position_to_hw(position)?.map(|v| vec![v]);
+            stop_animation(&evt.device, position);
+
+            let mapped = map_key_image_position_to_hw(&kind, &settings, position)?.map(|v| vec![v]);
 
             let Some(positions) = mapped else {
                 log::debug!(
@@ -507,19 +677,124 @@ pub async fn handle_set_image(device: &Device, evt: SetImageEvent) -> Result<(),
                 );
                 return Ok(());
             };
-            log::debug!("Clearing image at mapped positions={:?} (is_encoder={})", positions, is_encoder);
+
+            if is_spanned_over(&kind, &settings.layout, position, &positions) {
+                log::debug!("Ignoring clear for position={} covered by span", position);
+                return Ok(());
+            }
+
+            // Clearing the span position clears the whole strip
+            let positions = if settings.layout.span == Some(position) {
+                kind.segment_targets().to_vec()
+            } else {
+                positions
+            };
+
+            log::debug!(
+                "Clearing image at mapped positions={:?} (is_encoder={})",
+                positions,
+                is_encoder
+            );
             for hw_pos in positions {
-                device.clear_button_image(hw_pos).await?;
+                queue.send(ImageCommand::Clear(hw_pos)).ok();
             }
-            device.flush().await?;
         }
         (None, None) => {
             log::debug!("Clearing all button images");
-            device.clear_all_button_images().await?;
-            device.flush().await?;
+            stop_animations(&evt.device);
+            queue.send(ImageCommand::ClearAll).ok();
         }
         _ => {}
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        images::FitMode,
+        mappings::{KINDS, Rotation},
+    };
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use image::{GenericImageView, ImageFormat, Rgb, RgbImage};
+    use std::io::Cursor;
+
+    /// UI position of the leftmost LCD segment of an upright N1
+    const SPAN: u8 = 3;
+    /// Colours of the three parts of a spanned image, first to last
+    const COLORS: [[u8; 3]; 3] = [[255, 0, 0], [0, 255, 0], [0, 0, 255]];
+
+    /// Strip of three colours as data url, along its width or height
+    fn strip(width: u32, height: u32) -> String {
+        let image = RgbImage::from_fn(width, height, |x, y| {
+            let part = if width > height {
+                x * 3 / width
+            } else {
+                y * 3 / height
+            };
+            Rgb(COLORS[part as usize])
+        });
+
+        let mut png = Cursor::new(Vec::new());
+        image.write_to(&mut png, ImageFormat::Png).unwrap();
+        format!(
+            "data:image/png;base64,{}",
+            STANDARD.encode(png.into_inner())
+        )
+    }
+
+    /// Returns hardware targets of the first frame with the colour of their middle pixel
+    fn spanned(orientation: Rotation, data: &str) -> Vec<(u8, [u8; 3])> {
+        let kind = KINDS.first().unwrap().oriented(orientation);
+        let layout = ImageLayout {
+            fit: FitMode::Stretch,
+            span: Some(SPAN),
+            ..ImageLayout::default()
+        };
+
+        let frames = prepare_frames(&kind, &layout, SPAN, vec![15], data).unwrap();
+
+        frames[0]
+            .uploads
+            .iter()
+            .map(|(hw_pos, image)| {
+                assert_eq!(image.dimensions(), (64, 64));
+                (*hw_pos, image.to_rgb8().get_pixel(32, 32).0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spans_wide_image_left_to_right() {
+        assert_eq!(
+            spanned(Rotation::Rot0, &strip(192, 64)),
+            [(15, COLORS[0]), (16, COLORS[1]), (17, COLORS[2])]
+        );
+    }
+
+    #[test]
+    fn spans_across_segments_reversed_when_rotated() {
+        // Upside down the rightmost segment is the first one seen
+        assert_eq!(
+            spanned(Rotation::Rot180, &strip(192, 64)),
+            [(17, COLORS[0]), (16, COLORS[1]), (15, COLORS[2])]
+        );
+
+        // Turned sideways the segments form a column, top to bottom
+        assert_eq!(
+            spanned(Rotation::Rot90, &strip(64, 192)),
+            [(15, COLORS[0]), (16, COLORS[1]), (17, COLORS[2])]
+        );
+        assert_eq!(
+            spanned(Rotation::Rot270, &strip(64, 192)),
+            [(17, COLORS[0]), (16, COLORS[1]), (15, COLORS[2])]
+        );
+    }
+
+    #[test]
+    fn narrow_image_at_span_position_is_not_sliced() {
+        assert_eq!(spanned(Rotation::Rot0, &strip(64, 192)).len(), 1);
+    }
+}
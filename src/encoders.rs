@@ -0,0 +1,519 @@
+use openaction::OUTBOUND_EVENT_MANAGER;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc::UnboundedReceiver, time::sleep_until};
+
+use crate::{
+    config::config,
+    mappings::CandidateDevice,
+    monitor::{InputEvent, monitor},
+};
+
+/// Encoder settings from `[encoders]` of the config, or from the table of a single device
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct EncoderConfig {
+    /// Swap clockwise and counter-clockwise
+    pub invert: Option<bool>,
+    /// Multiplier applied to every detent
+    pub step: Option<i16>,
+    /// Twists arriving within this window are sent to OpenDeck as one change
+    pub coalesce_ms: Option<u64>,
+    /// Detents per second from which spinning gets accelerated, 0 disables acceleration
+    pub acceleration_threshold: Option<f32>,
+    /// Upper bound of the acceleration multiplier
+    pub acceleration_max: Option<f32>,
+}
+
+/// Encoder settings of a device, with device and global settings applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncoderSettings {
+    pub invert: bool,
+    pub step: i16,
+    pub coalesce_ms: u64,
+    pub acceleration_threshold: f32,
+    pub acceleration_max: f32,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        Self {
+            invert: false,
+            step: 1,
+            coalesce_ms: 30,
+            acceleration_threshold: 0.0,
+            acceleration_max: 5.0,
+        }
+    }
+}
+
+impl EncoderSettings {
+    /// Resolves settings of a device, the ones it sets itself win over the global ones
+    pub fn resolve(device: &EncoderConfig, global: &EncoderConfig) -> Self {
+        let defaults = Self::default();
+
+        Self {
+            invert: device.invert.or(global.invert).unwrap_or(defaults.invert),
+            step: device.step.or(global.step).unwrap_or(defaults.step),
+            coalesce_ms: device
+                .coalesce_ms
+                .or(global.coalesce_ms)
+                .unwrap_or(defaults.coalesce_ms),
+            acceleration_threshold: device
+                .acceleration_threshold
+                .or(global.acceleration_threshold)
+                .unwrap_or(defaults.acceleration_threshold),
+            acceleration_max: device
+                .acceleration_max
+                .or(global.acceleration_max)
+                .unwrap_or(defaults.acceleration_max),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.step <= 0 {
+            return Err(format!(
+                "step has to be at least 1, use invert to change direction, got {}",
+                self.step
+            ));
+        }
+
+        if !self.acceleration_threshold.is_finite() || self.acceleration_threshold < 0.0 {
+            return Err(format!(
+                "acceleration_threshold has to be 0 or more, got {}",
+                self.acceleration_threshold
+            ));
+        }
+
+        if !self.acceleration_max.is_finite() || self.acceleration_max < 1.0 {
+            return Err(format!(
+                "acceleration_max has to be 1 or more, got {}",
+                self.acceleration_max
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderEvent {
+    Down(u8),
+    Up(u8),
+    Twist(u8, i16),
+}
+
+/// Twists of one encoder waiting to be sent
+#[derive(Debug, Default)]
+struct PendingTwist {
+    delta: i16,
+    flush_at: Option<Instant>,
+    last_detent: Option<(Instant, i16)>,
+}
+
+/// Scales and coalesces encoder twists
+struct Encoders {
+    settings: EncoderSettings,
+    pending: HashMap<u8, PendingTwist>,
+}
+
+impl Encoders {
+    fn new(settings: EncoderSettings) -> Self {
+        Self {
+            settings,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Returns multiplier for a detent, based on how fast the encoder is spinning
+    fn acceleration(&self, pending: &PendingTwist, direction: i16, now: Instant) -> i16 {
+        if self.settings.acceleration_threshold <= 0.0 {
+            return 1;
+        }
+
+        let Some((last, last_direction)) = pending.last_detent else {
+            return 1;
+        };
+
+        // Changing direction means the user is fine-tuning, so start slow again
+        if last_direction != direction {
+            return 1;
+        }
+
+        let elapsed = now.duration_since(last).as_secs_f32().max(0.001);
+        let rate = 1.0 / elapsed;
+
+        (rate / self.settings.acceleration_threshold)
+            .clamp(1.0, self.settings.acceleration_max.max(1.0)) as i16
+    }
+
+    /// Returns events to send right away
+    fn input(&mut self, event: EncoderEvent, now: Instant) -> Vec<EncoderEvent> {
+        let (encoder, value) = match event {
+            EncoderEvent::Twist(encoder, value) => (encoder, value),
+            EncoderEvent::Down(encoder) | EncoderEvent::Up(encoder) => {
+                // Pending twists have to reach OpenDeck before the press changes
+                let mut events = self.flush(encoder);
+                events.push(event);
+                return events;
+            }
+        };
+
+        let direction = if self.settings.invert {
+            value.saturating_neg()
+        } else {
+            value
+        };
+        let mut pending = self.pending.remove(&encoder).unwrap_or_default();
+        let acceleration = self.acceleration(&pending, direction.signum(), now);
+
+        pending.delta = pending.delta.saturating_add(
+            direction
+                .saturating_mul(self.settings.step)
+                .saturating_mul(acceleration),
+        );
+        pending.last_detent = Some((now, direction.signum()));
+
+        if self.settings.coalesce_ms == 0 {
+            let delta = std::mem::take(&mut pending.delta);
+            self.pending.insert(encoder, pending);
+            return vec![EncoderEvent::Twist(encoder, delta)];
+        }
+
+        pending
+            .flush_at
+            .get_or_insert(now + Duration::from_millis(self.settings.coalesce_ms));
+        self.pending.insert(encoder, pending);
+
+        vec![]
+    }
+
+    /// Returns coalesced twists whose window has passed
+    fn tick(&mut self, now: Instant) -> Vec<EncoderEvent> {
+        let due: Vec<u8> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.flush_at.is_some_and(|at| now >= at))
+            .map(|(encoder, _)| *encoder)
+            .collect();
+
+        due.into_iter()
+            .flat_map(|encoder| self.flush(encoder))
+            .collect()
+    }
+
+    /// Returns pending twists of an encoder
+    fn flush(&mut self, encoder: u8) -> Vec<EncoderEvent> {
+        let Some(pending) = self.pending.get_mut(&encoder) else {
+            return vec![];
+        };
+
+        pending.flush_at = None;
+        match std::mem::take(&mut pending.delta) {
+            0 => vec![],
+            delta => vec![EncoderEvent::Twist(encoder, delta)],
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .filter_map(|pending| pending.flush_at)
+            .min()
+    }
+}
+
+/// Receives encoder events of a device and sends them to OpenDeck, scaled and coalesced
+pub async fn encoder_task(
+    candidate: &CandidateDevice,
+    mut events: UnboundedReceiver<EncoderEvent>,
+) {
+    let serial = candidate.dev.serial_number.as_deref();
+    let settings = config().for_serial(serial).encoders;
+    log::debug!("Encoder settings for {}: {:?}", candidate.id, settings);
+
+    let mut encoders = Encoders::new(settings);
+
+    loop {
+        let deadline = encoders.next_deadline();
+
+        let output = tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => {
+                    // Cheap enough to do on every event, and picks up reloaded settings
+                    encoders.settings = config().for_serial(serial).encoders;
+                    encoders.input(event, Instant::now())
+                }
+                None => break,
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                encoders.tick(Instant::now())
+            }
+        };
+
+        for event in output {
+            send_encoder_event(&candidate.id, event).await;
+        }
+    }
+}
+
+async fn send_encoder_event(id: &str, event: EncoderEvent) {
+    let mut outbound = OUTBOUND_EVENT_MANAGER.lock().await;
+    let Some(outbound) = outbound.as_mut() else {
+        if !monitor(InputEvent::Encoder(id.to_string(), event)) {
+            log::info!("Not connected to OpenDeck, dropping {:?} of {}", event, id);
+        }
+        return;
+    };
+
+    match event {
+        EncoderEvent::Down(encoder) => {
+            outbound
+                .encoder_down(id.to_string(), encoder)
+                .await
+                .unwrap();
+        }
+        EncoderEvent::Up(encoder) => {
+            outbound.encoder_up(id.to_string(), encoder).await.unwrap();
+        }
+        EncoderEvent::Twist(encoder, delta) => {
+            log::debug!(
+                "Sending encoder_change device={} encoder={} delta={}",
+                id,
+                encoder,
+                delta
+            );
+            outbound
+                .encoder_change(id.to_string(), encoder, delta)
+                .await
+                .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoders(settings: EncoderSettings) -> Encoders {
+        Encoders::new(settings)
+    }
+
+    fn immediate() -> EncoderSettings {
+        EncoderSettings {
+            coalesce_ms: 0,
+            ..EncoderSettings::default()
+        }
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn scales_detents_by_step() {
+        let mut encoders = encoders(EncoderSettings {
+            step: 3,
+            ..immediate()
+        });
+        let now = Instant::now();
+
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, 1), now),
+            [EncoderEvent::Twist(0, 3)]
+        );
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, -1), now + ms(500)),
+            [EncoderEvent::Twist(0, -3)]
+        );
+    }
+
+    #[test]
+    fn inverts_direction() {
+        let mut encoders = encoders(EncoderSettings {
+            invert: true,
+            ..immediate()
+        });
+
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, 1), Instant::now()),
+            [EncoderEvent::Twist(0, -1)]
+        );
+    }
+
+    #[test]
+    fn coalesces_twists_within_window() {
+        let mut encoders = encoders(EncoderSettings::default());
+        let start = Instant::now();
+
+        assert_eq!(encoders.input(EncoderEvent::Twist(0, 1), start), []);
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, 1), start + ms(10)),
+            []
+        );
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, 1), start + ms(20)),
+            []
+        );
+        assert_eq!(encoders.next_deadline(), Some(start + ms(30)));
+        assert_eq!(encoders.tick(start + ms(29)), []);
+        assert_eq!(encoders.tick(start + ms(30)), [EncoderEvent::Twist(0, 3)]);
+        assert_eq!(encoders.next_deadline(), None);
+
+        // The next twist opens a new window
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, -1), start + ms(40)),
+            []
+        );
+        assert_eq!(encoders.tick(start + ms(70)), [EncoderEvent::Twist(0, -1)]);
+    }
+
+    #[test]
+    fn press_flushes_pending_twists_first() {
+        let mut encoders = encoders(EncoderSettings::default());
+        let start = Instant::now();
+
+        encoders.input(EncoderEvent::Twist(0, 1), start);
+        encoders.input(EncoderEvent::Twist(0, 1), start + ms(5));
+        assert_eq!(
+            encoders.input(EncoderEvent::Down(0), start + ms(10)),
+            [EncoderEvent::Twist(0, 2), EncoderEvent::Down(0)]
+        );
+        assert_eq!(encoders.tick(start + ms(100)), []);
+    }
+
+    #[test]
+    fn accelerates_only_above_threshold() {
+        let mut encoders = encoders(EncoderSettings {
+            acceleration_threshold: 10.0,
+            acceleration_max: 5.0,
+            ..immediate()
+        });
+        let start = Instant::now();
+
+        // First detent, nothing to measure the speed against
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, 1), start),
+            [EncoderEvent::Twist(0, 1)]
+        );
+        // 5 detents per second, below the threshold
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, 1), start + ms(200)),
+            [EncoderEvent::Twist(0, 1)]
+        );
+        // 20 detents per second, twice the threshold
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, 1), start + ms(250)),
+            [EncoderEvent::Twist(0, 2)]
+        );
+        // 100 detents per second, capped at the maximum
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, 1), start + ms(260)),
+            [EncoderEvent::Twist(0, 5)]
+        );
+        // Changing direction starts slow again
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, -1), start + ms(270)),
+            [EncoderEvent::Twist(0, -1)]
+        );
+    }
+
+    #[test]
+    fn acceleration_is_off_by_default() {
+        let mut encoders = encoders(immediate());
+        let start = Instant::now();
+
+        encoders.input(EncoderEvent::Twist(0, 1), start);
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, 1), start + ms(1)),
+            [EncoderEvent::Twist(0, 1)]
+        );
+    }
+
+    #[test]
+    fn device_settings_override_global_ones() {
+        let global = EncoderConfig {
+            step: Some(2),
+            coalesce_ms: Some(10),
+            ..EncoderConfig::default()
+        };
+        let device = EncoderConfig {
+            step: Some(4),
+            invert: Some(true),
+            ..EncoderConfig::default()
+        };
+
+        let other = EncoderSettings::resolve(&EncoderConfig::default(), &global);
+        assert_eq!(
+            (other.step, other.invert, other.coalesce_ms),
+            (2, false, 10)
+        );
+
+        let device = EncoderSettings::resolve(&device, &global);
+        assert_eq!(
+            (device.step, device.invert, device.coalesce_ms),
+            (4, true, 10)
+        );
+    }
+
+    #[test]
+    fn large_steps_saturate() {
+        let mut encoders = encoders(EncoderSettings {
+            step: i16::MAX,
+            acceleration_threshold: 10.0,
+            acceleration_max: 1000.0,
+            ..immediate()
+        });
+        let start = Instant::now();
+
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, -1), start),
+            [EncoderEvent::Twist(0, -i16::MAX)]
+        );
+        // Accelerated on top of the step
+        assert_eq!(
+            encoders.input(EncoderEvent::Twist(0, -1), start + ms(1)),
+            [EncoderEvent::Twist(0, i16::MIN)]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert_eq!(EncoderSettings::default().validate(), Ok(()));
+
+        let invalid = [
+            EncoderConfig {
+                step: Some(0),
+                ..EncoderConfig::default()
+            },
+            EncoderConfig {
+                step: Some(-1),
+                ..EncoderConfig::default()
+            },
+            EncoderConfig {
+                acceleration_threshold: Some(-1.0),
+                ..EncoderConfig::default()
+            },
+            EncoderConfig {
+                acceleration_threshold: Some(f32::NAN),
+                ..EncoderConfig::default()
+            },
+            EncoderConfig {
+                acceleration_threshold: Some(f32::INFINITY),
+                ..EncoderConfig::default()
+            },
+            EncoderConfig {
+                acceleration_max: Some(0.5),
+                ..EncoderConfig::default()
+            },
+        ];
+
+        for config in invalid {
+            let settings = EncoderSettings::resolve(&config, &EncoderConfig::default());
+            assert!(settings.validate().is_err(), "{:?} is valid", settings);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use async_hid::HidError;
+use mirajazz::error::MirajazzError;
+use std::{error::Error, io};
+
+/// What to do about an error coming from the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Device is busy or went away for a moment, trying again may help
+    Retryable,
+    /// Device node can't be opened by this user, most likely missing udev rules
+    Permission,
+    /// Trying again won't change anything
+    Fatal,
+}
+
+/// Classifies an error by its variant and the io error underneath it, if there is one
+pub fn classify(err: &MirajazzError) -> ErrorClass {
+    match err {
+        MirajazzError::HidError(HidError::Disconnected | HidError::NotConnected) => {
+            ErrorClass::Retryable
+        }
+        // MirajazzError has no source, so the walk starts at the HID error
+        MirajazzError::HidError(hid) => match io_error(hid) {
+            Some(io) => classify_io(io),
+            // HID layer errors without an os error are hiccups of a device that was just plugged in
+            None => ErrorClass::Retryable,
+        },
+        _ => ErrorClass::Fatal,
+    }
+}
+
+fn classify_io(err: &io::Error) -> ErrorClass {
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => ErrorClass::Permission,
+        io::ErrorKind::ResourceBusy
+        | io::ErrorKind::NotFound
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::NotConnected => ErrorClass::Retryable,
+        _ => ErrorClass::Fatal,
+    }
+}
+
+/// Finds the first io error in the source chain
+fn io_error<'a>(err: &'a (dyn Error + 'static)) -> Option<&'a io::Error> {
+    let mut current = Some(err);
+
+    while let Some(err) = current {
+        if let Some(io) = err.downcast_ref::<io::Error>() {
+            return Some(io);
+        }
+
+        current = err.source();
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hid_io_error(kind: io::ErrorKind) -> MirajazzError {
+        MirajazzError::HidError(HidError::Other(Box::new(io::Error::from(kind))))
+    }
+
+    #[test]
+    fn permission_denied_needs_udev_rules() {
+        assert_eq!(
+            classify(&hid_io_error(io::ErrorKind::PermissionDenied)),
+            ErrorClass::Permission
+        );
+        assert_eq!(
+            classify(&MirajazzError::HidError(HidError::from(
+                io::Error::from_raw_os_error(13)
+            ))),
+            ErrorClass::Permission
+        );
+    }
+
+    #[test]
+    fn busy_and_gone_devices_are_retried() {
+        for kind in [io::ErrorKind::ResourceBusy, io::ErrorKind::NotFound] {
+            assert_eq!(classify(&hid_io_error(kind)), ErrorClass::Retryable);
+        }
+
+        for hid in [HidError::Disconnected, HidError::NotConnected] {
+            assert_eq!(
+                classify(&MirajazzError::HidError(hid)),
+                ErrorClass::Retryable
+            );
+        }
+    }
+
+    #[test]
+    fn other_errors_are_fatal() {
+        assert_eq!(
+            classify(&hid_io_error(io::ErrorKind::InvalidInput)),
+            ErrorClass::Fatal
+        );
+        assert_eq!(classify(&MirajazzError::BadData), ErrorClass::Fatal);
+    }
+}
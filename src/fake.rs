@@ -0,0 +1,675 @@
+use image::DynamicImage;
+use mirajazz::{
+    error::MirajazzError,
+    state::DeviceStateUpdate,
+    types::{DeviceInput, HidDeviceInfo, ImageFormat},
+};
+use std::{
+    collections::HashMap,
+    env, fmt,
+    net::SocketAddr,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, BufReader, stdin},
+    net::TcpListener,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    TRACKER,
+    hardware::{Hardware, HardwareFuture, InputProcessor, InputReader},
+    mappings::Kind,
+    simulator::Canvas,
+};
+
+/// Command a fake device received
+#[derive(Debug)]
+pub enum Command {
+    SetMode(u8),
+    SetBrightness(u8),
+    SetImage(u8, DynamicImage),
+    ClearImage(u8),
+    ClearAll,
+    Flush,
+    KeepAlive,
+    Shutdown,
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::SetMode(mode) => write!(f, "SetMode {}", mode),
+            Command::SetBrightness(brightness) => write!(f, "SetBrightness {}", brightness),
+            Command::SetImage(key, image) => {
+                write!(f, "SetImage {} {}x{}", key, image.width(), image.height())
+            }
+            Command::ClearImage(key) => write!(f, "ClearImage {}", key),
+            Command::ClearAll => write!(f, "ClearAll"),
+            Command::Flush => write!(f, "Flush"),
+            Command::KeepAlive => write!(f, "KeepAlive"),
+            Command::Shutdown => write!(f, "Shutdown"),
+        }
+    }
+}
+
+/// Serial numbers of fake devices to connect, from `OPENDECK_AKP05_FAKE_DEVICES` as a comma separated list
+pub fn fake_serials() -> Vec<String> {
+    env::var("OPENDECK_AKP05_FAKE_DEVICES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|serial| !serial.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn fake_device_id(serial: &str) -> Option<async_hid::DeviceId> {
+    Some(async_hid::DeviceId::DevPath(
+        format!("/dev/fake-{}", serial).into(),
+    ))
+}
+
+#[cfg(target_os = "macos")]
+fn fake_device_id(_serial: &str) -> Option<async_hid::DeviceId> {
+    Some(async_hid::DeviceId::RegistryEntryId(0))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn fake_device_id(_serial: &str) -> Option<async_hid::DeviceId> {
+    None
+}
+
+/// Returns HID info a fake device of a kind shows up with
+pub fn fake_device_info(serial: &str, kind: &Kind) -> Option<HidDeviceInfo> {
+    let (vendor_id, product_id) = kind.vid_pid();
+
+    Some(HidDeviceInfo {
+        id: fake_device_id(serial)?,
+        name: format!("Fake {}", kind.human_name()),
+        product_id,
+        vendor_id,
+        usage_id: kind.profile().usage_id,
+        usage_page: kind.profile().usage_page,
+        serial_number: Some(serial.to_string()),
+    })
+}
+
+/// Shared between a fake device and its handle
+struct FakeState {
+    serial: String,
+    /// Commands received so far, as logged
+    #[cfg(test)]
+    history: Mutex<Vec<String>>,
+    input: UnboundedSender<(u8, u8)>,
+    receiver: Arc<tokio::sync::Mutex<UnboundedReceiver<(u8, u8)>>>,
+    /// Error returned by the next operation, to simulate a failing device
+    failure: Mutex<Option<MirajazzError>>,
+    key_count: usize,
+    encoder_count: usize,
+}
+
+/// Fakes connected so far, keyed by serial number, so input can reach a device
+/// `device_task` connected on its own
+static FAKES: LazyLock<Mutex<HashMap<String, Arc<FakeState>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Returns handle of the fake device connected with a serial number
+pub fn fake_device(serial: &str) -> Option<FakeHandle> {
+    FAKES
+        .lock()
+        .unwrap()
+        .get(serial)
+        .cloned()
+        .map(|state| FakeHandle { state })
+}
+
+/// Presses keys of a fake device from the outside
+pub struct FakeHandle {
+    state: Arc<FakeState>,
+}
+
+impl FakeHandle {
+    /// Feeds a raw `(input, state)` pair as if it was read from the device
+    pub fn send_raw(&self, input: u8, state: u8) {
+        self.state.input.send((input, state)).ok();
+    }
+
+    /// Makes the next operation on the device fail with an error
+    pub fn fail_next(&self, err: MirajazzError) {
+        *self.state.failure.lock().unwrap() = Some(err);
+    }
+
+    /// Returns commands the device received so far
+    #[cfg(test)]
+    pub fn commands(&self) -> Vec<String> {
+        self.state.history.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// In-memory device logging every command it gets, as `FAKE <serial> <command>`
+pub struct FakeDevice {
+    vid: u16,
+    pid: u16,
+    state: Arc<FakeState>,
+    /// Simulated screens, when enabled
+    canvas: Option<Mutex<Canvas>>,
+}
+
+impl FakeDevice {
+    fn record(&self, command: Command) -> HardwareFuture<'_, ()> {
+        let result = match self.state.failure.lock().unwrap().take() {
+            Some(err) => Err(err),
+            None => {
+                log::info!("FAKE {} {}", self.state.serial, command);
+
+                #[cfg(test)]
+                self.state.history.lock().unwrap().push(command.to_string());
+
+                if let Some(canvas) = &self.canvas {
+                    canvas.lock().unwrap().apply(command);
+                }
+
+                Ok(())
+            }
+        };
+
+        Box::pin(async move { result })
+    }
+}
+
+impl Hardware for FakeDevice {
+    async fn connect(
+        dev: &HidDeviceInfo,
+        _protocol_version: usize,
+        key_count: usize,
+        encoder_count: usize,
+    ) -> Result<Self, MirajazzError> {
+        let serial = dev.serial_number.clone().unwrap_or_default();
+        let (input, receiver) = unbounded_channel();
+        let state = Arc::new(FakeState {
+            serial: serial.clone(),
+            #[cfg(test)]
+            history: Mutex::new(Vec::new()),
+            input,
+            receiver: Arc::new(tokio::sync::Mutex::new(receiver)),
+            failure: Mutex::new(None),
+            key_count,
+            encoder_count,
+        });
+
+        FAKES.lock().unwrap().insert(serial.clone(), state.clone());
+
+        Ok(Self {
+            vid: dev.vendor_id,
+            pid: dev.product_id,
+            canvas: Canvas::new(&serial, dev.vendor_id, dev.product_id).map(Mutex::new),
+            state,
+        })
+    }
+
+    async fn is_present(_dev: &HidDeviceInfo) -> bool {
+        true
+    }
+
+    fn vid_pid(&self) -> (u16, u16) {
+        (self.vid, self.pid)
+    }
+
+    fn set_mode(&self, mode: u8) -> HardwareFuture<'_, ()> {
+        self.record(Command::SetMode(mode))
+    }
+
+    fn set_brightness(&self, brightness: u8) -> HardwareFuture<'_, ()> {
+        self.record(Command::SetBrightness(brightness))
+    }
+
+    fn set_button_image(
+        &self,
+        key: u8,
+        _format: ImageFormat,
+        image: DynamicImage,
+    ) -> HardwareFuture<'_, ()> {
+        self.record(Command::SetImage(key, image))
+    }
+
+    fn clear_button_image(&self, key: u8) -> HardwareFuture<'_, ()> {
+        self.record(Command::ClearImage(key))
+    }
+
+    fn clear_all_button_images(&self) -> HardwareFuture<'_, ()> {
+        self.record(Command::ClearAll)
+    }
+
+    fn flush(&self) -> HardwareFuture<'_, ()> {
+        self.record(Command::Flush)
+    }
+
+    fn keep_alive(&self) -> HardwareFuture<'_, ()> {
+        self.record(Command::KeepAlive)
+    }
+
+    fn shutdown(&self) -> HardwareFuture<'_, ()> {
+        self.record(Command::Shutdown)
+    }
+
+    fn reader(&self, process: InputProcessor) -> Arc<dyn InputReader> {
+        Arc::new(FakeReader {
+            receiver: self.state.receiver.clone(),
+            process,
+            buttons: Mutex::new(vec![false; self.state.key_count]),
+            encoders: Mutex::new(vec![false; self.state.encoder_count]),
+        })
+    }
+}
+
+/// Turns injected input into updates the same way the mirajazz reader does
+struct FakeReader {
+    receiver: Arc<tokio::sync::Mutex<UnboundedReceiver<(u8, u8)>>>,
+    process: InputProcessor,
+    buttons: Mutex<Vec<bool>>,
+    encoders: Mutex<Vec<bool>>,
+}
+
+impl FakeReader {
+    fn updates(&self, input: DeviceInput) -> Vec<DeviceStateUpdate> {
+        let mut updates = Vec::new();
+
+        match input {
+            DeviceInput::NoData => {}
+            DeviceInput::ButtonStateChange(states) => {
+                let mut buttons = self.buttons.lock().unwrap();
+                for (index, (new, old)) in states.iter().zip(buttons.iter_mut()).enumerate() {
+                    if new != old {
+                        updates.push(match new {
+                            true => DeviceStateUpdate::ButtonDown(index as u8),
+                            false => DeviceStateUpdate::ButtonUp(index as u8),
+                        });
+                        *old = *new;
+                    }
+                }
+            }
+            DeviceInput::EncoderStateChange(states) => {
+                let mut encoders = self.encoders.lock().unwrap();
+                for (index, (new, old)) in states.iter().zip(encoders.iter_mut()).enumerate() {
+                    if new != old {
+                        updates.push(match new {
+                            true => DeviceStateUpdate::EncoderDown(index as u8),
+                            false => DeviceStateUpdate::EncoderUp(index as u8),
+                        });
+                        *old = *new;
+                    }
+                }
+            }
+            DeviceInput::EncoderTwist(ticks) => {
+                for (index, ticks) in ticks.into_iter().enumerate() {
+                    if ticks != 0 {
+                        updates.push(DeviceStateUpdate::EncoderTwist(index as u8, ticks));
+                    }
+                }
+            }
+        }
+
+        updates
+    }
+}
+
+impl InputReader for FakeReader {
+    fn read(&self, wait: Option<Duration>) -> HardwareFuture<'_, Vec<DeviceStateUpdate>> {
+        Box::pin(async move {
+            let mut receiver = self.receiver.lock().await;
+            let received = match wait {
+                Some(wait) => timeout(wait, receiver.recv()).await.ok().flatten(),
+                None => receiver.recv().await,
+            };
+            drop(receiver);
+
+            let Some((input, state)) = received else {
+                return Ok(Vec::new());
+            };
+
+            Ok(self.updates((self.process)(input, state)?))
+        })
+    }
+}
+
+/// Feeds fake devices with input, one line each: `<serial> <input> <state>` to send raw input,
+/// or `<serial> fail` to fail the next operation
+fn handle_input(line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((serial, args)) = words.split_first() else {
+        return;
+    };
+
+    let Some(device) = fake_device(serial) else {
+        log::warn!("No fake device with serial {}", serial);
+        return;
+    };
+
+    match args {
+        ["fail"] => device.fail_next(MirajazzError::DeviceNotFoundError),
+        [input, state] => match (input.parse::<u8>(), state.parse::<u8>()) {
+            (Ok(input), Ok(state)) => device.send_raw(input, state),
+            _ => log::warn!("Invalid fake device input: {}", line),
+        },
+        _ => log::warn!("Invalid fake device input: {}", line),
+    }
+}
+
+/// Handles input lines until the reader ends or the task is cancelled
+async fn feed_lines(reader: impl AsyncBufRead + Unpin, token: CancellationToken) {
+    let mut lines = reader.lines();
+
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line,
+            _ = token.cancelled() => break,
+        };
+
+        match line {
+            Ok(Some(line)) => handle_input(&line),
+            Ok(None) => break,
+            Err(err) => {
+                log::error!("Failed to read fake device input: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+/// Parses the address to take fake device input on. Anyone able to connect could press keys and
+/// fail devices, so only loopback addresses are accepted
+fn loopback_address(address: &str) -> Result<SocketAddr, String> {
+    let address = address
+        .trim()
+        .parse::<SocketAddr>()
+        .map_err(|err| format!("invalid address {:?}: {}", address, err))?;
+
+    if !address.ip().is_loopback() {
+        return Err(format!(
+            "{} is not a loopback address, use e.g. 127.0.0.1:7700",
+            address
+        ));
+    }
+
+    Ok(address)
+}
+
+/// Takes fake device input from stdin, and from connections to `OPENDECK_AKP05_FAKE_INPUT_SOCKET`
+/// if it is set to a loopback address such as `127.0.0.1:7700`
+pub async fn fake_input_task(token: CancellationToken) {
+    let tracker = TRACKER.lock().await.clone();
+
+    tracker.spawn(feed_lines(BufReader::new(stdin()), token.clone()));
+
+    let Ok(address) = env::var("OPENDECK_AKP05_FAKE_INPUT_SOCKET") else {
+        return;
+    };
+
+    let address = match loopback_address(&address) {
+        Ok(address) => address,
+        Err(err) => {
+            log::error!("Not listening for fake device input: {}", err);
+            return;
+        }
+    };
+
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Failed to listen on {}: {}", address, err);
+            return;
+        }
+    };
+
+    log::info!("Listening for fake device input on {}", address);
+
+    loop {
+        let connection = tokio::select! {
+            connection = listener.accept() => connection,
+            _ = token.cancelled() => break,
+        };
+
+        match connection {
+            Ok((stream, peer)) => {
+                log::info!("Fake device input connected from {}", peer);
+                tracker.spawn(feed_lines(BufReader::new(stream), token.clone()));
+            }
+            Err(err) => log::warn!("Failed to accept fake device input: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        DEVICES, TOKENS,
+        device::device_task,
+        inputs::{INPUT_DEVICE, process_input_n1},
+        mappings::{CandidateDevice, KINDS},
+        queue::{ImageCommand, image_queue},
+        watcher::device_info_to_candidate,
+    };
+    use tokio::{
+        task::JoinHandle,
+        time::{Instant, sleep},
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn candidate(serial: &str) -> CandidateDevice {
+        fake_device_info(serial, KINDS.first().unwrap())
+            .and_then(device_info_to_candidate)
+            .expect("fake devices are not supported on this platform")
+    }
+
+    fn names(updates: &[DeviceStateUpdate]) -> Vec<String> {
+        updates
+            .iter()
+            .map(|update| format!("{:?}", update))
+            .collect()
+    }
+
+    /// Waits until a fake device received a command
+    async fn wait_for_command(serial: &str, command: &str) -> FakeHandle {
+        let started = Instant::now();
+
+        loop {
+            if let Some(device) = fake_device(serial)
+                && device.commands().iter().any(|logged| logged == command)
+            {
+                return device;
+            }
+
+            assert!(
+                started.elapsed() < TIMEOUT,
+                "{} never got {}",
+                serial,
+                command
+            );
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Runs a device session of a fake device, the way the supervisor does
+    async fn start_session(serial: &str) -> (CandidateDevice, CancellationToken, JoinHandle<()>) {
+        let candidate = candidate(serial);
+        let token = CancellationToken::new();
+        TOKENS
+            .write()
+            .await
+            .insert(candidate.id.clone(), token.clone());
+
+        let task = tokio::spawn(device_task::<FakeDevice>(candidate.clone(), token.clone()));
+
+        // Flushing the cleared screens is the last step before the device is registered
+        wait_for_command(serial, "Flush").await;
+        while image_queue(&candidate.id).await.is_none() {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        (candidate, token, task)
+    }
+
+    #[tokio::test]
+    async fn session_initializes_and_shuts_down_device() {
+        let (_, token, task) = start_session("lifecycle").await;
+        let device = fake_device("lifecycle").unwrap();
+
+        assert_eq!(
+            device.commands(),
+            ["SetMode 3", "SetBrightness 50", "ClearAll", "Flush"]
+        );
+
+        token.cancel();
+        timeout(TIMEOUT, task).await.unwrap().unwrap();
+
+        assert_eq!(device.commands().last().unwrap(), "Shutdown");
+    }
+
+    #[tokio::test]
+    async fn failing_device_ends_session() {
+        let (candidate, _, task) = start_session("failing").await;
+        let device = fake_device("failing").unwrap();
+
+        device.fail_next(MirajazzError::DeviceNotFoundError);
+        let (_, queue) = image_queue(&candidate.id).await.unwrap();
+        queue.send(ImageCommand::ClearAll).unwrap();
+
+        // Session ends on its own, without being cancelled
+        timeout(TIMEOUT, task).await.unwrap().unwrap();
+
+        assert!(!DEVICES.read().await.contains_key(&candidate.id));
+        // Failed command is not recorded, and a failed device is not shut down politely
+        assert_eq!(device.commands().last().unwrap(), "Flush");
+    }
+
+    #[tokio::test]
+    async fn fail_next_fails_only_one_operation() {
+        let info = fake_device_info("fail-next", KINDS.first().unwrap()).unwrap();
+        let device = FakeDevice::connect(&info, 3, 17, 1).await.unwrap();
+        let handle = fake_device("fail-next").unwrap();
+
+        handle.fail_next(MirajazzError::BadData);
+        assert!(matches!(
+            device.set_brightness(10).await,
+            Err(MirajazzError::BadData)
+        ));
+        assert!(device.set_brightness(20).await.is_ok());
+        assert_eq!(handle.commands(), ["SetBrightness 20"]);
+    }
+
+    #[tokio::test]
+    async fn raw_input_is_decoded_into_updates() {
+        let candidate = candidate("raw-input");
+        let device = FakeDevice::connect(
+            &candidate.dev,
+            candidate.kind.protocol_version(),
+            candidate.kind.key_count(),
+            candidate.kind.encoder_count(),
+        )
+        .await
+        .unwrap();
+        let reader = device.reader(process_input_n1);
+        let handle = fake_device("raw-input").unwrap();
+
+        let read = async || {
+            INPUT_DEVICE
+                .scope(candidate.clone(), reader.read(Some(TIMEOUT)))
+                .await
+                .unwrap()
+        };
+
+        // First keypad key, the encoder turning both ways and its press, as the N1 reports them
+        let expected = [
+            ((0x01, 0x01), "ButtonDown(0)"),
+            ((0x01, 0x00), "ButtonUp(0)"),
+            ((0x33, 0x01), "EncoderTwist(0, 1)"),
+            ((0x32, 0x01), "EncoderTwist(0, -1)"),
+            ((0x23, 0x01), "EncoderDown(0)"),
+            ((0x23, 0x00), "EncoderUp(0)"),
+        ];
+
+        for ((input, state), update) in expected {
+            handle.send_raw(input, state);
+            assert_eq!(names(&read().await), [update], "input 0x{:02x}", input);
+        }
+
+        // Status frames and unknown codes produce nothing
+        handle.send_raw(0xcc, 0xff);
+        handle.send_raw(0x7f, 0x01);
+        assert!(read().await.is_empty());
+        assert!(read().await.is_empty());
+    }
+
+    #[test]
+    fn reader_reports_only_changes() {
+        let (_, receiver) = unbounded_channel();
+        let reader = FakeReader {
+            receiver: Arc::new(tokio::sync::Mutex::new(receiver)),
+            process: process_input_n1,
+            buttons: Mutex::new(vec![false; 3]),
+            encoders: Mutex::new(vec![false; 2]),
+        };
+
+        let sequence = [
+            (
+                DeviceInput::ButtonStateChange(vec![true, false, false]),
+                vec!["ButtonDown(0)"],
+            ),
+            // Same state again, e.g. a repeated report
+            (
+                DeviceInput::ButtonStateChange(vec![true, false, false]),
+                vec![],
+            ),
+            (
+                DeviceInput::ButtonStateChange(vec![false, true, true]),
+                vec!["ButtonUp(0)", "ButtonDown(1)", "ButtonDown(2)"],
+            ),
+            (
+                DeviceInput::EncoderStateChange(vec![false, true]),
+                vec!["EncoderDown(1)"],
+            ),
+            (
+                DeviceInput::EncoderStateChange(vec![false, false]),
+                vec!["EncoderUp(1)"],
+            ),
+            (
+                DeviceInput::EncoderTwist(vec![0, -2]),
+                vec!["EncoderTwist(1, -2)"],
+            ),
+            (DeviceInput::EncoderTwist(vec![0, 0]), vec![]),
+            (DeviceInput::NoData, vec![]),
+        ];
+
+        for (input, expected) in sequence {
+            let description = format!("{:?}", input);
+            assert_eq!(names(&reader.updates(input)), expected, "{}", description);
+        }
+    }
+
+    #[test]
+    fn input_socket_only_listens_on_loopback() {
+        for address in ["127.0.0.1:7700", "[::1]:7700", " 127.0.0.2:7700 "] {
+            assert!(loopback_address(address).is_ok(), "{} is rejected", address);
+        }
+
+        for address in [
+            "0.0.0.0:7700",
+            "192.168.1.10:7700",
+            "[::]:7700",
+            "localhost",
+        ] {
+            assert!(
+                loopback_address(address).is_err(),
+                "{} is accepted",
+                address
+            );
+        }
+    }
+}
@@ -0,0 +1,660 @@
+use openaction::OUTBOUND_EVENT_MANAGER;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc::UnboundedReceiver, time::sleep_until};
+
+use crate::{
+    config::config,
+    mappings::{CandidateDevice, Kind},
+    monitor::{InputEvent, monitor},
+};
+
+/// Gesture settings from `[gestures]` of the config, or from the table of a single device
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct GestureConfig {
+    /// How long a key has to be held to count as long press
+    pub long_press_ms: Option<u64>,
+    /// How long to wait for a second press after a release
+    pub double_press_ms: Option<u64>,
+    /// Delay before a held key starts repeating
+    pub repeat_delay_ms: Option<u64>,
+    /// Interval between repeats of a held key
+    pub repeat_interval_ms: Option<u64>,
+    /// Extra input-only rows registered with OpenDeck to bind virtual positions to
+    pub virtual_rows: Option<usize>,
+    /// Replaces the global keys when set for a device
+    pub keys: Option<Vec<KeyGestures>>,
+}
+
+/// Gestures of one key, identified by its UI position
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyGestures {
+    pub position: u8,
+    /// Position triggered instead when the key is held
+    #[serde(default)]
+    pub long_press: Option<u8>,
+    /// Position triggered instead when the key is pressed twice
+    #[serde(default)]
+    pub double_press: Option<u8>,
+    /// Repeat key presses while the key is held
+    #[serde(default)]
+    pub repeat: bool,
+}
+
+/// Gesture settings of a device, without any configured keys every press is passed through as-is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GestureSettings {
+    pub long_press_ms: u64,
+    pub double_press_ms: u64,
+    pub repeat_delay_ms: u64,
+    pub repeat_interval_ms: u64,
+    pub virtual_rows: usize,
+    pub keys: Vec<KeyGestures>,
+}
+
+impl Default for GestureSettings {
+    fn default() -> Self {
+        Self {
+            long_press_ms: 500,
+            double_press_ms: 250,
+            repeat_delay_ms: 400,
+            repeat_interval_ms: 100,
+            virtual_rows: 0,
+            keys: Vec::new(),
+        }
+    }
+}
+
+impl GestureSettings {
+    /// Resolves settings of a device, the ones it sets itself win over the global ones
+    pub fn resolve(device: &GestureConfig, global: &GestureConfig) -> Self {
+        let defaults = Self::default();
+
+        Self {
+            long_press_ms: device
+                .long_press_ms
+                .or(global.long_press_ms)
+                .unwrap_or(defaults.long_press_ms),
+            double_press_ms: device
+                .double_press_ms
+                .or(global.double_press_ms)
+                .unwrap_or(defaults.double_press_ms),
+            repeat_delay_ms: device
+                .repeat_delay_ms
+                .or(global.repeat_delay_ms)
+                .unwrap_or(defaults.repeat_delay_ms),
+            repeat_interval_ms: device
+                .repeat_interval_ms
+                .or(global.repeat_interval_ms)
+                .unwrap_or(defaults.repeat_interval_ms),
+            virtual_rows: device
+                .virtual_rows
+                .or(global.virtual_rows)
+                .unwrap_or(defaults.virtual_rows),
+            keys: device
+                .keys
+                .as_ref()
+                .or(global.keys.as_ref())
+                .cloned()
+                .unwrap_or(defaults.keys),
+        }
+    }
+
+    /// Returns number of positions registered with OpenDeck for a device, virtual rows included
+    pub fn position_count(&self, kind: &Kind) -> usize {
+        (kind.row_count() + self.virtual_rows) * kind.col_count()
+    }
+
+    /// Checks the settings make sense, with every target registered on each of `kinds`
+    pub fn validate(&self, kinds: &[Kind]) -> Result<(), String> {
+        if self.repeat_interval_ms == 0 {
+            return Err("repeat_interval_ms has to be at least 1".to_string());
+        }
+
+        for (index, key) in self.keys.iter().enumerate() {
+            if key.repeat && (key.long_press.is_some() || key.double_press.is_some()) {
+                return Err(format!(
+                    "key {} can't both repeat and have a long or double press",
+                    key.position
+                ));
+            }
+
+            for kind in kinds {
+                let positions = self.position_count(kind);
+
+                if let Some(target) = [Some(key.position), key.long_press, key.double_press]
+                    .into_iter()
+                    .flatten()
+                    .find(|target| *target as usize >= positions)
+                {
+                    return Err(format!(
+                        "position {} of key {} is outside of the {} positions of {} mounted at {:?}",
+                        target,
+                        key.position,
+                        positions,
+                        kind.human_name(),
+                        kind.orientation()
+                    ));
+                }
+            }
+
+            if self.keys[..index]
+                .iter()
+                .any(|other| other.position == key.position)
+            {
+                return Err(format!("key {} is configured twice", key.position));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn key(&self, position: u8) -> Option<&KeyGestures> {
+        self.keys.iter().find(|key| key.position == position)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Down(u8),
+    Up(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum KeyState {
+    /// Key is held, waiting for long press or repeating
+    Held {
+        since: Instant,
+        long_fired: bool,
+        next_repeat: Option<Instant>,
+    },
+    /// Key was released, waiting for a second press
+    WaitingDouble { until: Instant },
+    /// Second press of a double press is held
+    DoubleHeld,
+}
+
+/// Turns physical key presses into key events, synthesizing gestures
+struct Gestures {
+    settings: GestureSettings,
+    keys: HashMap<u8, KeyState>,
+}
+
+impl Gestures {
+    fn new(settings: GestureSettings) -> Self {
+        Self {
+            settings,
+            keys: HashMap::new(),
+        }
+    }
+
+    fn input(&mut self, event: KeyEvent, now: Instant) -> Vec<KeyEvent> {
+        let position = match event {
+            KeyEvent::Down(position) | KeyEvent::Up(position) => position,
+        };
+
+        let Some(gestures) = self.settings.key(position) else {
+            return vec![event];
+        };
+
+        let state = self.keys.remove(&position);
+
+        match (event, state) {
+            (KeyEvent::Down(_), Some(KeyState::WaitingDouble { .. })) => {
+                let double = gestures.double_press.unwrap_or(position);
+                self.keys.insert(position, KeyState::DoubleHeld);
+                vec![KeyEvent::Down(double)]
+            }
+            (KeyEvent::Down(_), _) => {
+                let next_repeat = gestures
+                    .repeat
+                    .then(|| now + Duration::from_millis(self.settings.repeat_delay_ms));
+                self.keys.insert(
+                    position,
+                    KeyState::Held {
+                        since: now,
+                        long_fired: false,
+                        next_repeat,
+                    },
+                );
+
+                if gestures.repeat {
+                    vec![KeyEvent::Down(position)]
+                } else {
+                    vec![]
+                }
+            }
+            (KeyEvent::Up(_), Some(KeyState::Held { long_fired, .. })) => {
+                if gestures.repeat {
+                    vec![KeyEvent::Up(position)]
+                } else if let (true, Some(long)) = (long_fired, gestures.long_press) {
+                    vec![KeyEvent::Up(long)]
+                } else if gestures.double_press.is_some() {
+                    let until = now + Duration::from_millis(self.settings.double_press_ms);
+                    self.keys
+                        .insert(position, KeyState::WaitingDouble { until });
+                    vec![]
+                } else {
+                    vec![KeyEvent::Down(position), KeyEvent::Up(position)]
+                }
+            }
+            (KeyEvent::Up(_), Some(KeyState::DoubleHeld)) => {
+                vec![KeyEvent::Up(gestures.double_press.unwrap_or(position))]
+            }
+            (KeyEvent::Up(_), state) => {
+                // Release without a matching press, keep whatever we were waiting for
+                if let Some(state) = state {
+                    self.keys.insert(position, state);
+                }
+                vec![]
+            }
+        }
+    }
+
+    /// Fires gestures whose time has come
+    fn tick(&mut self, now: Instant) -> Vec<KeyEvent> {
+        let mut events = vec![];
+        let long_press = Duration::from_millis(self.settings.long_press_ms);
+        let repeat_interval = Duration::from_millis(self.settings.repeat_interval_ms);
+
+        let mut positions: Vec<u8> = self.keys.keys().copied().collect();
+        positions.sort();
+
+        for position in positions {
+            let Some(gestures) = self.settings.key(position) else {
+                continue;
+            };
+
+            match self.keys.get_mut(&position) {
+                Some(KeyState::Held {
+                    since,
+                    long_fired,
+                    next_repeat,
+                }) => {
+                    if let Some(long) = gestures.long_press
+                        && !*long_fired
+                        && now >= *since + long_press
+                    {
+                        *long_fired = true;
+                        events.push(KeyEvent::Down(long));
+                    }
+
+                    if let Some(at) = next_repeat
+                        && now >= *at
+                    {
+                        *at += repeat_interval;
+                        events.push(KeyEvent::Up(position));
+                        events.push(KeyEvent::Down(position));
+                    }
+                }
+                Some(KeyState::WaitingDouble { until }) if now >= *until => {
+                    self.keys.remove(&position);
+                    events.push(KeyEvent::Down(position));
+                    events.push(KeyEvent::Up(position));
+                }
+                _ => {}
+            }
+        }
+
+        events
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let long_press = Duration::from_millis(self.settings.long_press_ms);
+
+        self.keys
+            .iter()
+            .filter_map(|(position, state)| match state {
+                KeyState::Held {
+                    since,
+                    long_fired,
+                    next_repeat,
+                } => {
+                    let long = self
+                        .settings
+                        .key(*position)
+                        .and_then(|gestures| gestures.long_press)
+                        .filter(|_| !long_fired)
+                        .map(|_| *since + long_press);
+
+                    long.into_iter().chain(*next_repeat).min()
+                }
+                KeyState::WaitingDouble { until } => Some(*until),
+                KeyState::DoubleHeld => None,
+            })
+            .min()
+    }
+}
+
+/// Returns true if position is one of the extra rows, which only exist to bind gestures to
+pub fn is_virtual_position(kind: &Kind, gestures: &GestureSettings, position: u8) -> bool {
+    let grid = kind.row_count() * kind.col_count();
+
+    (grid..gestures.position_count(kind)).contains(&(position as usize))
+}
+
+/// Receives key presses of a device and sends them to OpenDeck, applying configured gestures
+pub async fn gesture_task(candidate: &CandidateDevice, mut events: UnboundedReceiver<KeyEvent>) {
+    let serial = candidate.dev.serial_number.as_deref();
+    let mut gestures = Gestures::new(config().for_serial(serial).gestures);
+
+    loop {
+        let deadline = gestures.next_deadline();
+
+        let output = tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => {
+                    // Reloaded settings are picked up once no key is in the middle of a gesture
+                    if gestures.keys.is_empty() {
+                        let current = config().for_serial(serial).gestures;
+                        if current != gestures.settings {
+                            gestures = Gestures::new(current);
+                        }
+                    }
+
+                    gestures.input(event, Instant::now())
+                }
+                None => break,
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                gestures.tick(Instant::now())
+            }
+        };
+
+        for event in output {
+            send_key_event(&candidate.id, event).await;
+        }
+    }
+}
+
+async fn send_key_event(id: &str, event: KeyEvent) {
+    let mut outbound = OUTBOUND_EVENT_MANAGER.lock().await;
+    let Some(outbound) = outbound.as_mut() else {
+        if !monitor(InputEvent::Key(id.to_string(), event)) {
+            log::info!("Not connected to OpenDeck, dropping {:?} of {}", event, id);
+        }
+        return;
+    };
+
+    match event {
+        KeyEvent::Down(position) => {
+            log::debug!("Sending key_down device={} key={}", id, position);
+            outbound.key_down(id.to_string(), position).await.unwrap();
+        }
+        KeyEvent::Up(position) => {
+            log::debug!("Sending key_up device={} key={}", id, position);
+            outbound.key_up(id.to_string(), position).await.unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappings::{KINDS, Rotation};
+
+    fn gestures(settings: GestureSettings) -> Gestures {
+        Gestures::new(settings)
+    }
+
+    fn config(keys: Vec<KeyGestures>) -> GestureSettings {
+        GestureSettings {
+            keys,
+            ..GestureSettings::default()
+        }
+    }
+
+    fn key(position: u8) -> KeyGestures {
+        KeyGestures {
+            position,
+            long_press: None,
+            double_press: None,
+            repeat: false,
+        }
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn passes_through_keys_without_gestures() {
+        let mut gestures = gestures(config(vec![]));
+        let now = Instant::now();
+
+        assert_eq!(gestures.input(KeyEvent::Down(6), now), [KeyEvent::Down(6)]);
+        assert_eq!(gestures.input(KeyEvent::Up(6), now), [KeyEvent::Up(6)]);
+        assert_eq!(gestures.next_deadline(), None);
+    }
+
+    #[test]
+    fn tap_is_sent_on_release() {
+        let mut gestures = gestures(config(vec![KeyGestures {
+            long_press: Some(21),
+            ..key(6)
+        }]));
+        let start = Instant::now();
+
+        assert_eq!(gestures.input(KeyEvent::Down(6), start), []);
+        assert_eq!(gestures.tick(start + ms(100)), []);
+        assert_eq!(
+            gestures.input(KeyEvent::Up(6), start + ms(100)),
+            [KeyEvent::Down(6), KeyEvent::Up(6)]
+        );
+        assert_eq!(gestures.next_deadline(), None);
+    }
+
+    #[test]
+    fn long_press_fires_while_held() {
+        let mut gestures = gestures(config(vec![KeyGestures {
+            long_press: Some(21),
+            ..key(6)
+        }]));
+        let start = Instant::now();
+
+        gestures.input(KeyEvent::Down(6), start);
+        assert_eq!(gestures.next_deadline(), Some(start + ms(500)));
+        assert_eq!(gestures.tick(start + ms(499)), []);
+        assert_eq!(gestures.tick(start + ms(500)), [KeyEvent::Down(21)]);
+        assert_eq!(gestures.tick(start + ms(900)), []);
+        assert_eq!(
+            gestures.input(KeyEvent::Up(6), start + ms(900)),
+            [KeyEvent::Up(21)]
+        );
+    }
+
+    #[test]
+    fn double_press_replaces_both_presses() {
+        let mut gestures = gestures(config(vec![KeyGestures {
+            double_press: Some(22),
+            ..key(6)
+        }]));
+        let start = Instant::now();
+
+        gestures.input(KeyEvent::Down(6), start);
+        assert_eq!(gestures.input(KeyEvent::Up(6), start + ms(50)), []);
+        assert_eq!(gestures.next_deadline(), Some(start + ms(300)));
+        assert_eq!(
+            gestures.input(KeyEvent::Down(6), start + ms(150)),
+            [KeyEvent::Down(22)]
+        );
+        assert_eq!(
+            gestures.input(KeyEvent::Up(6), start + ms(200)),
+            [KeyEvent::Up(22)]
+        );
+        assert_eq!(gestures.tick(start + ms(1000)), []);
+    }
+
+    #[test]
+    fn single_press_is_sent_once_double_press_window_passes() {
+        let mut gestures = gestures(config(vec![KeyGestures {
+            double_press: Some(22),
+            ..key(6)
+        }]));
+        let start = Instant::now();
+
+        gestures.input(KeyEvent::Down(6), start);
+        gestures.input(KeyEvent::Up(6), start + ms(50));
+        assert_eq!(gestures.tick(start + ms(299)), []);
+        assert_eq!(
+            gestures.tick(start + ms(300)),
+            [KeyEvent::Down(6), KeyEvent::Up(6)]
+        );
+        assert_eq!(gestures.next_deadline(), None);
+    }
+
+    #[test]
+    fn repeat_starts_after_delay_and_keeps_interval() {
+        let mut gestures = gestures(config(vec![KeyGestures {
+            repeat: true,
+            ..key(7)
+        }]));
+        let start = Instant::now();
+
+        assert_eq!(
+            gestures.input(KeyEvent::Down(7), start),
+            [KeyEvent::Down(7)]
+        );
+        assert_eq!(gestures.tick(start + ms(399)), []);
+
+        for repeat in 0..3 {
+            let at = start + ms(400 + repeat * 100);
+            assert_eq!(gestures.next_deadline(), Some(at));
+            assert_eq!(gestures.tick(at), [KeyEvent::Up(7), KeyEvent::Down(7)]);
+        }
+
+        assert_eq!(
+            gestures.input(KeyEvent::Up(7), start + ms(650)),
+            [KeyEvent::Up(7)]
+        );
+        assert_eq!(gestures.next_deadline(), None);
+    }
+
+    fn n1(orientation: Rotation) -> Kind {
+        KINDS.first().unwrap().oriented(orientation)
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let valid = GestureSettings {
+            virtual_rows: 1,
+            ..config(vec![
+                KeyGestures {
+                    long_press: Some(21),
+                    double_press: Some(22),
+                    ..key(6)
+                },
+                KeyGestures {
+                    repeat: true,
+                    ..key(7)
+                },
+            ])
+        };
+        assert_eq!(valid.validate(&[n1(Rotation::Rot0)]), Ok(()));
+
+        let invalid = [
+            config(vec![KeyGestures {
+                repeat: true,
+                long_press: Some(21),
+                ..key(6)
+            }]),
+            config(vec![KeyGestures {
+                repeat: true,
+                double_press: Some(22),
+                ..key(6)
+            }]),
+            config(vec![key(6), key(6)]),
+            config(vec![KeyGestures {
+                long_press: Some(24),
+                ..key(6)
+            }]),
+            config(vec![KeyGestures {
+                double_press: Some(30),
+                ..key(6)
+            }]),
+            config(vec![key(24)]),
+            GestureSettings {
+                repeat_interval_ms: 0,
+                ..config(vec![])
+            },
+        ];
+
+        for config in invalid {
+            let config = GestureSettings {
+                virtual_rows: 1,
+                ..config
+            };
+            assert!(
+                config.validate(&[n1(Rotation::Rot0)]).is_err(),
+                "{:?} is valid",
+                config
+            );
+        }
+    }
+
+    #[test]
+    fn targets_have_to_be_registered_on_every_mounting() {
+        // An N1 has 7x3 keys, with a virtual row that is 24 positions upright and 28 sideways
+        let last = GestureSettings {
+            virtual_rows: 1,
+            ..config(vec![KeyGestures {
+                long_press: Some(23),
+                ..key(6)
+            }])
+        };
+        assert_eq!(last.position_count(&n1(Rotation::Rot0)), 24);
+        assert_eq!(
+            last.validate(&[n1(Rotation::Rot0), n1(Rotation::Rot90)]),
+            Ok(())
+        );
+
+        let past = GestureSettings {
+            virtual_rows: 1,
+            ..config(vec![KeyGestures {
+                long_press: Some(24),
+                ..key(6)
+            }])
+        };
+        assert_eq!(past.validate(&[n1(Rotation::Rot90)]), Ok(()));
+        assert!(past.validate(&[n1(Rotation::Rot0)]).is_err());
+        assert!(
+            past.validate(&[n1(Rotation::Rot90), n1(Rotation::Rot0)])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn device_settings_override_global_ones() {
+        let global = GestureConfig {
+            long_press_ms: Some(800),
+            virtual_rows: Some(1),
+            keys: Some(vec![key(6)]),
+            ..GestureConfig::default()
+        };
+        let device = GestureConfig {
+            long_press_ms: Some(300),
+            keys: Some(vec![key(7), key(8)]),
+            ..GestureConfig::default()
+        };
+
+        let settings = GestureSettings::resolve(&device, &global);
+        assert_eq!(settings.long_press_ms, 300);
+        assert_eq!(settings.virtual_rows, 1);
+        assert_eq!(settings.double_press_ms, 250);
+        assert_eq!(settings.keys, [key(7), key(8)]);
+
+        let settings = GestureSettings::resolve(&GestureConfig::default(), &global);
+        assert_eq!(settings.long_press_ms, 800);
+        assert_eq!(settings.keys, [key(6)]);
+
+        assert_eq!(
+            GestureSettings::resolve(&GestureConfig::default(), &GestureConfig::default()),
+            GestureSettings::default()
+        );
+    }
+}
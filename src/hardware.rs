@@ -0,0 +1,141 @@
+use image::DynamicImage;
+use mirajazz::{
+    device::{Device, list_devices},
+    error::MirajazzError,
+    state::{DeviceStateReader, DeviceStateUpdate},
+    types::{DeviceInput, HidDeviceInfo, ImageFormat},
+};
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use crate::mappings::QUERIES;
+
+/// Future returned by hardware operations, boxed so devices can be used as trait objects
+pub type HardwareFuture<'a, T> =
+    Pin<Box<dyn Future<Output = Result<T, MirajazzError>> + Send + 'a>>;
+
+/// Turns raw `(input, state)` pairs into device input
+pub type InputProcessor = fn(u8, u8) -> Result<DeviceInput, MirajazzError>;
+
+/// Everything the plugin does with a device, so something else than real hardware can stand in for it
+pub trait Hardware: Send + Sync {
+    fn connect(
+        dev: &HidDeviceInfo,
+        protocol_version: usize,
+        key_count: usize,
+        encoder_count: usize,
+    ) -> impl Future<Output = Result<Self, MirajazzError>> + Send
+    where
+        Self: Sized;
+
+    /// Returns true if the device is still plugged in
+    fn is_present(dev: &HidDeviceInfo) -> impl Future<Output = bool> + Send
+    where
+        Self: Sized;
+
+    fn vid_pid(&self) -> (u16, u16);
+
+    fn set_mode(&self, mode: u8) -> HardwareFuture<'_, ()>;
+
+    fn set_brightness(&self, brightness: u8) -> HardwareFuture<'_, ()>;
+
+    fn set_button_image(
+        &self,
+        key: u8,
+        format: ImageFormat,
+        image: DynamicImage,
+    ) -> HardwareFuture<'_, ()>;
+
+    fn clear_button_image(&self, key: u8) -> HardwareFuture<'_, ()>;
+
+    fn clear_all_button_images(&self) -> HardwareFuture<'_, ()>;
+
+    /// Shows images uploaded since the last flush
+    fn flush(&self) -> HardwareFuture<'_, ()>;
+
+    fn keep_alive(&self) -> HardwareFuture<'_, ()>;
+
+    fn shutdown(&self) -> HardwareFuture<'_, ()>;
+
+    fn reader(&self, process: InputProcessor) -> Arc<dyn InputReader>;
+}
+
+/// Source of input updates of a device
+pub trait InputReader: Send + Sync {
+    fn read(&self, timeout: Option<Duration>) -> HardwareFuture<'_, Vec<DeviceStateUpdate>>;
+}
+
+impl Hardware for Device {
+    async fn connect(
+        dev: &HidDeviceInfo,
+        protocol_version: usize,
+        key_count: usize,
+        encoder_count: usize,
+    ) -> Result<Self, MirajazzError> {
+        Device::connect(dev, protocol_version, key_count, encoder_count).await
+    }
+
+    async fn is_present(dev: &HidDeviceInfo) -> bool {
+        match list_devices(QUERIES.as_slice()).await {
+            Ok(devices) => devices.iter().any(|other| {
+                other.vendor_id == dev.vendor_id
+                    && other.product_id == dev.product_id
+                    && other.serial_number == dev.serial_number
+            }),
+            Err(err) => {
+                log::warn!("Failed to list devices: {}", err);
+                false
+            }
+        }
+    }
+
+    fn vid_pid(&self) -> (u16, u16) {
+        (self.vid, self.pid)
+    }
+
+    fn set_mode(&self, mode: u8) -> HardwareFuture<'_, ()> {
+        Box::pin(Device::set_mode(self, mode))
+    }
+
+    fn set_brightness(&self, brightness: u8) -> HardwareFuture<'_, ()> {
+        Box::pin(Device::set_brightness(self, brightness))
+    }
+
+    fn set_button_image(
+        &self,
+        key: u8,
+        format: ImageFormat,
+        image: DynamicImage,
+    ) -> HardwareFuture<'_, ()> {
+        Box::pin(Device::set_button_image(self, key, format, image))
+    }
+
+    fn clear_button_image(&self, key: u8) -> HardwareFuture<'_, ()> {
+        Box::pin(Device::clear_button_image(self, key))
+    }
+
+    fn clear_all_button_images(&self) -> HardwareFuture<'_, ()> {
+        Box::pin(Device::clear_all_button_images(self))
+    }
+
+    fn flush(&self) -> HardwareFuture<'_, ()> {
+        Box::pin(Device::flush(self))
+    }
+
+    fn keep_alive(&self) -> HardwareFuture<'_, ()> {
+        Box::pin(Device::keep_alive(self))
+    }
+
+    fn shutdown(&self) -> HardwareFuture<'_, ()> {
+        Box::pin(Device::shutdown(self))
+    }
+
+    fn reader(&self, process: InputProcessor) -> Arc<dyn InputReader> {
+        self.get_reader(process)
+    }
+}
+
+impl InputReader for DeviceStateReader {
+    fn read(&self, timeout: Option<Duration>) -> HardwareFuture<'_, Vec<DeviceStateUpdate>> {
+        Box::pin(DeviceStateReader::read(self, timeout))
+    }
+}
@@ -0,0 +1,602 @@
+use data_url::{DataUrl, DataUrlError, forgiving_base64::InvalidBase64};
+use image::{
+    AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits, Rgb, RgbImage,
+    codecs::gif::GifDecoder, imageops, imageops::FilterType,
+};
+use serde::Deserialize;
+use std::{env, fmt, io::Cursor, sync::LazyLock, thread::available_parallelism, time::Duration};
+use tokio::{sync::Semaphore, task::JoinError};
+
+/// Largest data url accepted from OpenDeck, anything bigger can't be a sensible key image
+const MAX_DATA_URL_LEN: usize = 8 * 1024 * 1024;
+/// Largest width or height of a decoded image
+const MAX_IMAGE_DIMENSION: u32 = 4096;
+/// Most frames kept of an animation, longer ones are cut short
+const MAX_ANIMATION_FRAMES: usize = 200;
+/// Delay used for frames asking for less, the way browsers treat them
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+/// Frame delays below this are taken as unset
+const MIN_FRAME_DELAY: Duration = Duration::from_millis(20);
+/// Most images processed at once, so a burst of big images can't take over every core
+const MAX_IMAGE_WORKERS: usize = 4;
+
+/// Permits for image processing on the blocking thread pool
+static IMAGE_WORKERS: LazyLock<Semaphore> = LazyLock::new(|| {
+    let workers = available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+        .min(MAX_IMAGE_WORKERS);
+
+    Semaphore::new(workers)
+});
+
+/// Reasons an image sent by OpenDeck can't be shown
+#[derive(Debug)]
+pub enum ImageError {
+    TooLarge(usize),
+    InvalidDataUrl(DataUrlError),
+    InvalidBase64(InvalidBase64),
+    Empty,
+    UnsupportedMime(String),
+    Decode(image::ImageError),
+    Worker(JoinError),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge(len) => write!(
+                f,
+                "data url is {} bytes, limit is {}",
+                len, MAX_DATA_URL_LEN
+            ),
+            Self::InvalidDataUrl(err) => write!(f, "invalid data url: {}", err),
+            Self::InvalidBase64(err) => write!(f, "invalid base64 in data url: {}", err),
+            Self::Empty => write!(f, "data url has no image data"),
+            Self::UnsupportedMime(mime) => write!(f, "unsupported mime type: {}", mime),
+            Self::Decode(err) => write!(f, "failed to decode image: {}", err),
+            Self::Worker(err) => write!(f, "image worker failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidDataUrl(err) => Some(err),
+            Self::InvalidBase64(err) => Some(err),
+            Self::Decode(err) => Some(err),
+            Self::Worker(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<image::ImageError> for ImageError {
+    fn from(err: image::ImageError) -> Self {
+        Self::Decode(err)
+    }
+}
+
+/// Runs image work on the blocking thread pool, so decoding and resizing never hold up
+/// the async tasks handling device input. At most a few jobs run at the same time
+pub async fn run_image_job<T, F>(job: F) -> Result<T, ImageError>
+where
+    F: FnOnce() -> Result<T, ImageError> + Send + 'static,
+    T: Send + 'static,
+{
+    // Semaphore is never closed, so acquiring can't fail
+    let _permit = IMAGE_WORKERS.acquire().await.unwrap();
+
+    tokio::task::spawn_blocking(job)
+        .await
+        .map_err(ImageError::Worker)?
+}
+
+/// How images are fitted into the screens of the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FitMode {
+    /// Stretch to the screen size, ignoring aspect ratio
+    Stretch,
+    /// Scale to fit inside the screen, filling the rest with letterbox colour
+    Contain,
+    /// Scale to fill the screen, cropping what doesn't fit
+    Cover,
+}
+
+/// How images are laid out on the screens of a device, with config and environment applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageLayout {
+    pub fit: FitMode,
+    /// UI position where wide images get spread across all screen segments, if enabled
+    pub span: Option<u8>,
+    /// Colour transparent images are composited onto, as the device only shows opaque images
+    pub background: Rgb<u8>,
+    /// Colour of the bars around images in contain mode
+    pub letterbox: Rgb<u8>,
+}
+
+impl Default for ImageLayout {
+    fn default() -> Self {
+        Self {
+            fit: FitMode::Stretch,
+            span: None,
+            background: Rgb([0, 0, 0]),
+            letterbox: Rgb([0, 0, 0]),
+        }
+    }
+}
+
+impl ImageLayout {
+    /// Resolves layout from config values, environment variables taking precedence.
+    /// Colours are validated with the config, so invalid ones can only come from the environment
+    pub fn resolve(
+        fit: Option<FitMode>,
+        span: Option<u8>,
+        background: Option<&str>,
+        letterbox: Option<&str>,
+    ) -> Self {
+        let background = ENV_BACKGROUND
+            .or_else(|| background.and_then(parse_color))
+            .unwrap_or(Rgb([0, 0, 0]));
+
+        Self {
+            fit: ENV_FIT.or(fit).unwrap_or(FitMode::Stretch),
+            span: ENV_SPAN.or(span),
+            background,
+            // Bars blend in with transparent areas unless asked otherwise
+            letterbox: ENV_LETTERBOX
+                .or_else(|| letterbox.and_then(parse_color))
+                .unwrap_or(background),
+        }
+    }
+}
+
+/// Reads a colour from the environment, logging invalid ones
+fn env_color(name: &str) -> Option<Rgb<u8>> {
+    let raw = env::var(name).ok()?;
+
+    parse_color(&raw).or_else(|| {
+        log::error!("Invalid {} {:?}, ignoring it", name, raw);
+        None
+    })
+}
+
+static ENV_BACKGROUND: LazyLock<Option<Rgb<u8>>> =
+    LazyLock::new(|| env_color("OPENDECK_AKP05_IMAGE_BACKGROUND"));
+
+static ENV_LETTERBOX: LazyLock<Option<Rgb<u8>>> =
+    LazyLock::new(|| env_color("OPENDECK_AKP05_IMAGE_LETTERBOX"));
+
+static ENV_FIT: LazyLock<Option<FitMode>> = LazyLock::new(|| {
+    let raw = env::var("OPENDECK_AKP05_IMAGE_FIT").ok()?;
+
+    match raw.to_ascii_lowercase().as_str() {
+        "stretch" => Some(FitMode::Stretch),
+        "contain" => Some(FitMode::Contain),
+        "cover" => Some(FitMode::Cover),
+        _ => {
+            log::error!("Invalid OPENDECK_AKP05_IMAGE_FIT {:?}, ignoring it", raw);
+            None
+        }
+    }
+});
+
+static ENV_SPAN: LazyLock<Option<u8>> = LazyLock::new(|| {
+    let raw = env::var("OPENDECK_AKP05_LCD_SPAN").ok()?;
+
+    raw.parse::<u8>()
+        .inspect_err(|_| log::error!("Invalid OPENDECK_AKP05_LCD_SPAN {:?}, ignoring it", raw))
+        .ok()
+});
+
+/// Parses `#rrggbb` or `rrggbb` colours
+pub fn parse_color(raw: &str) -> Option<Rgb<u8>> {
+    let hex = raw.trim().trim_start_matches('#');
+
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+
+    let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16).ok();
+
+    Some(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+/// Frame of a decoded image, still images are a single frame without delay
+pub struct Frame {
+    pub image: DynamicImage,
+    pub delay: Duration,
+}
+
+/// Decodes an image OpenDeck sent as data url. Frames of animations are decoded as
+/// the iterator is consumed, so they can be processed one by one
+pub fn decode_data_url(
+    data: &str,
+    background: Rgb<u8>,
+) -> Result<Box<dyn Iterator<Item = Result<Frame, ImageError>>>, ImageError> {
+    if data.len() > MAX_DATA_URL_LEN {
+        return Err(ImageError::TooLarge(data.len()));
+    }
+
+    // OpenDeck sends image as a data url, so parse it using a library
+    let url = DataUrl::process(data).map_err(ImageError::InvalidDataUrl)?;
+    let (body, _fragment) = url.decode_to_vec().map_err(ImageError::InvalidBase64)?;
+
+    if body.is_empty() {
+        return Err(ImageError::Empty);
+    }
+
+    let format = match url.mime_type().subtype.as_str() {
+        "jpeg" | "jpg" => ImageFormat::Jpeg,
+        "png" => ImageFormat::Png,
+        "bmp" | "x-bmp" | "x-ms-bmp" => ImageFormat::Bmp,
+        "webp" => ImageFormat::WebP,
+        "gif" => ImageFormat::Gif,
+        _ => return Err(ImageError::UnsupportedMime(url.mime_type().to_string())),
+    };
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+
+    if format == ImageFormat::Gif {
+        let mut decoder = GifDecoder::new(Cursor::new(body))?;
+        decoder.set_limits(limits)?;
+
+        let frames = decoder
+            .into_frames()
+            .take(MAX_ANIMATION_FRAMES)
+            .map(move |frame| {
+                let frame = frame?;
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                let delay = Duration::from_millis((numer / denom.max(1)) as u64);
+
+                Ok(Frame {
+                    delay: if delay < MIN_FRAME_DELAY {
+                        DEFAULT_FRAME_DELAY
+                    } else {
+                        delay
+                    },
+                    image: flatten(DynamicImage::ImageRgba8(frame.into_buffer()), background),
+                })
+            });
+
+        return Ok(Box::new(frames));
+    }
+
+    let mut reader = ImageReader::with_format(Cursor::new(body), format);
+    reader.limits(limits);
+    let image = reader.decode()?;
+
+    Ok(Box::new(std::iter::once(Ok(Frame {
+        image: flatten(image, background),
+        delay: Duration::ZERO,
+    }))))
+}
+
+/// Composites images with transparency onto a solid background
+fn flatten(image: DynamicImage, background: Rgb<u8>) -> DynamicImage {
+    if !image.color().has_alpha() {
+        return image;
+    }
+
+    let rgba = image.to_rgba8();
+    let mut flat = RgbImage::new(rgba.width(), rgba.height());
+
+    for (src, dst) in rgba.pixels().zip(flat.pixels_mut()) {
+        let alpha = src[3] as u16;
+
+        for channel in 0..3 {
+            dst[channel] = ((src[channel] as u16 * alpha
+                + background[channel] as u16 * (255 - alpha))
+                / 255) as u8;
+        }
+    }
+
+    DynamicImage::ImageRgb8(flat)
+}
+
+/// Resizes image to the screen size according to the fit mode
+pub fn fit_image(image: &DynamicImage, size: (usize, usize), layout: &ImageLayout) -> DynamicImage {
+    let (width, height) = (size.0 as u32, size.1 as u32);
+
+    if image.width() == width && image.height() == height {
+        return image.clone();
+    }
+
+    match layout.fit {
+        FitMode::Stretch => image.resize_exact(width, height, FilterType::Lanczos3),
+        FitMode::Cover => image.resize_to_fill(width, height, FilterType::Lanczos3),
+        FitMode::Contain => {
+            let scaled = image.resize(width, height, FilterType::Lanczos3).to_rgb8();
+            let mut canvas = RgbImage::from_pixel(width, height, layout.letterbox);
+
+            imageops::overlay(
+                &mut canvas,
+                &scaled,
+                ((width - scaled.width()) / 2) as i64,
+                ((height - scaled.height()) / 2) as i64,
+            );
+
+            DynamicImage::ImageRgb8(canvas)
+        }
+    }
+}
+
+/// Fits image to a strip of equally sized tiles and cuts it into them,
+/// left to right or top to bottom for vertical strips
+pub fn slice_strip(
+    image: &DynamicImage,
+    tile_size: (usize, usize),
+    count: usize,
+    vertical: bool,
+    layout: &ImageLayout,
+) -> Vec<DynamicImage> {
+    let strip_size = if vertical {
+        (tile_size.0, tile_size.1 * count)
+    } else {
+        (tile_size.0 * count, tile_size.1)
+    };
+    let strip = fit_image(image, strip_size, layout);
+
+    (0..count)
+        .map(|index| {
+            let (x, y) = if vertical {
+                (0, index * tile_size.1)
+            } else {
+                (index * tile_size.0, 0)
+            };
+
+            strip.crop_imm(x as u32, y as u32, tile_size.0 as u32, tile_size.1 as u32)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use image::{GenericImageView, Rgba, RgbaImage};
+
+    fn decode_error(data: &str) -> ImageError {
+        match decode_data_url(data, Rgb([0, 0, 0])) {
+            Ok(_) => panic!("{:?} decoded", data),
+            Err(err) => err,
+        }
+    }
+
+    fn data_url(image: &DynamicImage, format: ImageFormat) -> String {
+        let mut encoded = Cursor::new(Vec::new());
+        image.write_to(&mut encoded, format).unwrap();
+
+        format!(
+            "data:{};base64,{}",
+            format.to_mime_type(),
+            STANDARD.encode(encoded.into_inner())
+        )
+    }
+
+    fn png_data_url(image: &RgbImage) -> String {
+        data_url(&DynamicImage::ImageRgb8(image.clone()), ImageFormat::Png)
+    }
+
+    fn decode_single(data: &str, background: Rgb<u8>) -> DynamicImage {
+        let mut frames = decode_data_url(data, background).unwrap();
+        let frame = frames.next().unwrap().unwrap();
+        assert!(frames.next().is_none());
+
+        frame.image
+    }
+
+    /// Small image with a different colour in every pixel
+    fn gradient(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| Rgb([x as u8 * 60, y as u8 * 100, 30]))
+    }
+
+    #[test]
+    fn rejects_oversized_data_url() {
+        let data = format!("data:image/png;base64,{}", "A".repeat(MAX_DATA_URL_LEN));
+
+        assert!(matches!(
+            decode_error(&data),
+            ImageError::TooLarge(len) if len == data.len()
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_data_url() {
+        assert!(matches!(
+            decode_error("not a data url"),
+            ImageError::InvalidDataUrl(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_base64() {
+        assert!(matches!(
+            decode_error("data:image/png;base64,!!!!"),
+            ImageError::InvalidBase64(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_empty_image() {
+        assert!(matches!(
+            decode_error("data:image/png;base64,"),
+            ImageError::Empty
+        ));
+    }
+
+    #[test]
+    fn rejects_unsupported_mime() {
+        assert!(matches!(
+            decode_error("data:image/svg+xml;base64,PHN2Zz4="),
+            ImageError::UnsupportedMime(mime) if mime == "image/svg+xml"
+        ));
+    }
+
+    #[test]
+    fn rejects_undecodable_image() {
+        assert!(matches!(
+            decode_error("data:image/png;base64,AAAAAAAA"),
+            ImageError::Decode(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_failed_worker() {
+        let result: Result<(), _> = run_image_job(|| panic!("worker panicked")).await;
+
+        assert!(matches!(result, Err(ImageError::Worker(_))));
+    }
+
+    #[test]
+    fn decodes_png() {
+        let image = gradient(4, 2);
+        let mut frames = decode_data_url(&png_data_url(&image), Rgb([0, 0, 0])).unwrap();
+        let frame = frames.next().unwrap().unwrap();
+
+        assert_eq!(frame.delay, Duration::ZERO);
+        assert_eq!(frame.image.to_rgb8(), image);
+        assert!(frames.next().is_none());
+    }
+
+    #[test]
+    fn composites_transparency_onto_background() {
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, Rgba([255, 0, 0, 128]));
+        image.put_pixel(1, 0, Rgba([10, 20, 30, 0]));
+
+        let flat = flatten(DynamicImage::ImageRgba8(image), Rgb([0, 0, 255])).to_rgb8();
+
+        assert_eq!(flat.get_pixel(0, 0), &Rgb([128, 0, 127]));
+        assert_eq!(flat.get_pixel(1, 0), &Rgb([0, 0, 255]));
+    }
+
+    #[test]
+    fn decoded_transparent_png_uses_background() {
+        let image = RgbaImage::from_pixel(3, 3, Rgba([200, 100, 0, 0]));
+        let data = data_url(&DynamicImage::ImageRgba8(image), ImageFormat::Png);
+
+        let decoded = decode_single(&data, Rgb([0x10, 0x20, 0x30]));
+
+        assert_eq!(
+            decoded.to_rgb8(),
+            RgbImage::from_pixel(3, 3, Rgb([0x10, 0x20, 0x30]))
+        );
+    }
+
+    #[test]
+    fn decodes_bmp() {
+        let image = gradient(3, 2);
+        let data = data_url(&DynamicImage::ImageRgb8(image.clone()), ImageFormat::Bmp);
+
+        assert!(data.starts_with("data:image/bmp;"));
+        assert_eq!(decode_single(&data, Rgb([0, 0, 0])).to_rgb8(), image);
+    }
+
+    #[test]
+    fn decodes_webp() {
+        // Encoded lossless, so pixels come back unchanged
+        let image = gradient(3, 2);
+        let data = data_url(&DynamicImage::ImageRgb8(image.clone()), ImageFormat::WebP);
+
+        assert!(data.starts_with("data:image/webp;"));
+        assert_eq!(decode_single(&data, Rgb([0, 0, 0])).to_rgb8(), image);
+    }
+
+    fn layout(fit: FitMode) -> ImageLayout {
+        ImageLayout {
+            fit,
+            letterbox: Rgb([1, 2, 3]),
+            ..ImageLayout::default()
+        }
+    }
+
+    /// Wide image of three square blocks: red, green and blue from left to right
+    fn blocks() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(12, 4, |x, _| match x / 4 {
+            0 => Rgb([255, 0, 0]),
+            1 => Rgb([0, 255, 0]),
+            _ => Rgb([0, 0, 255]),
+        }))
+    }
+
+    fn close_to(pixel: &Rgb<u8>, expected: Rgb<u8>) -> bool {
+        pixel
+            .0
+            .iter()
+            .zip(expected.0)
+            .all(|(channel, expected)| channel.abs_diff(expected) <= 8)
+    }
+
+    #[test]
+    fn stretch_fills_screen() {
+        let fitted = fit_image(&blocks(), (6, 6), &layout(FitMode::Stretch)).to_rgb8();
+
+        assert_eq!(fitted.dimensions(), (6, 6));
+        assert!(close_to(fitted.get_pixel(0, 0), Rgb([255, 0, 0])));
+        assert!(close_to(fitted.get_pixel(0, 5), Rgb([255, 0, 0])));
+        assert!(close_to(fitted.get_pixel(5, 5), Rgb([0, 0, 255])));
+    }
+
+    #[test]
+    fn contain_letterboxes_image() {
+        // 12x4 scales to 6x2, centered with two rows of bars above and below
+        let fitted = fit_image(&blocks(), (6, 6), &layout(FitMode::Contain)).to_rgb8();
+
+        assert_eq!(fitted.dimensions(), (6, 6));
+        for y in [0, 1, 4, 5] {
+            for x in 0..6 {
+                assert_eq!(fitted.get_pixel(x, y), &Rgb([1, 2, 3]), "{}x{}", x, y);
+            }
+        }
+        assert!(close_to(fitted.get_pixel(0, 2), Rgb([255, 0, 0])));
+        assert!(close_to(fitted.get_pixel(5, 3), Rgb([0, 0, 255])));
+    }
+
+    #[test]
+    fn cover_crops_to_center() {
+        let fitted = fit_image(&blocks(), (4, 4), &layout(FitMode::Cover)).to_rgb8();
+
+        assert_eq!(fitted.dimensions(), (4, 4));
+        for pixel in fitted.pixels() {
+            assert!(close_to(pixel, Rgb([0, 255, 0])), "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn keeps_image_of_screen_size() {
+        let image = DynamicImage::ImageRgb8(gradient(3, 2));
+
+        for fit in [FitMode::Stretch, FitMode::Contain, FitMode::Cover] {
+            assert_eq!(fit_image(&image, (3, 2), &layout(fit)), image);
+        }
+    }
+
+    #[test]
+    fn slices_horizontal_strip_left_to_right() {
+        let tiles = slice_strip(&blocks(), (4, 4), 3, false, &layout(FitMode::Stretch));
+
+        assert_eq!(tiles.len(), 3);
+        for (tile, color) in tiles.iter().zip([[255, 0, 0], [0, 255, 0], [0, 0, 255]]) {
+            assert_eq!(tile.dimensions(), (4, 4));
+            // Every tile is a block of its own, so together they cover the whole strip
+            assert_eq!(tile.to_rgb8(), RgbImage::from_pixel(4, 4, Rgb(color)));
+        }
+    }
+
+    #[test]
+    fn slices_vertical_strip_top_to_bottom() {
+        let tall = DynamicImage::ImageRgb8(imageops::rotate90(&blocks().to_rgb8()));
+        let tiles = slice_strip(&tall, (4, 4), 3, true, &layout(FitMode::Stretch));
+
+        assert_eq!(tiles.len(), 3);
+        for (tile, color) in tiles.iter().zip([[255, 0, 0], [0, 255, 0], [0, 0, 255]]) {
+            assert_eq!(tile.dimensions(), (4, 4));
+            assert_eq!(tile.to_rgb8(), RgbImage::from_pixel(4, 4, Rgb(color)));
+        }
+    }
+}
@@ -1,25 +1,76 @@
 use mirajazz::{error::MirajazzError, types::DeviceInput};
-use std::sync::Mutex;
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use crate::{
+    capture::capture_input,
+    mappings::{CandidateDevice, Kind},
+};
+
+tokio::task_local! {
+    /// Device whose reader is being polled. The reader only accepts a plain function
+    /// as input parser, so this is how the parser finds the device it decodes input for
+    pub static INPUT_DEVICE: CandidateDevice;
+}
 
-const KEY_COUNT_N1: usize = 17;
-const ENCODER_COUNT_N1: usize = 1;
+/// Last known button and encoder states of a device
+#[derive(Debug, Default)]
+struct InputState {
+    buttons: Vec<bool>,
+    encoders: Vec<bool>,
+}
 
-static ENCODER_STATES: Mutex<[bool; ENCODER_COUNT_N1]> = Mutex::new([false; ENCODER_COUNT_N1]);
+/// Input states of all devices, keyed by device id
+static INPUT_STATES: LazyLock<Mutex<HashMap<String, InputState>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Drops tracked input state of a device, so it starts fresh when reconnected
+pub fn forget_device(id: &str) {
+    INPUT_STATES.lock().unwrap().remove(id);
+}
 
 pub fn process_input_n1(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
     log::debug!("Processing input (N1): {input}=0x{input:02x}=0b{input:08b}, {state}");
 
-    // N1 periodically emits this non-input status frame.
-    if input == 0xcc && state == 0xff {
-        log::debug!("Ignoring N1 status frame: code=0xcc state=0xff");
+    let Ok(decoded) = INPUT_DEVICE.try_with(|device| {
+        capture_input(device, input, state);
+        decode_input_n1(device, input, state)
+    }) else {
+        log::error!("Input 0x{input:02x} received outside of a device reader, ignoring");
+        return Ok(DeviceInput::NoData);
+    };
+
+    decoded
+}
+
+fn decode_input_n1(
+    device: &CandidateDevice,
+    input: u8,
+    state: u8,
+) -> Result<DeviceInput, MirajazzError> {
+    let kind = &device.kind;
+    let inputs = &kind.profile().inputs;
+
+    // N1 periodically emits non-input status frames.
+    if inputs.ignore.contains(&(input, state)) {
+        log::debug!("Ignoring N1 status frame: code=0x{input:02x} state=0x{state:02x}");
         return Ok(DeviceInput::NoData);
     }
 
-    let decoded = match input {
-        (0x01..=0x0f) | 0x1e | 0x1f => read_button_press_n1(input, state),
-        0x32 | 0x33 => read_encoder_value_n1(input),
-        0x23 => read_encoder_press_n1(state),
-        _ => Err(MirajazzError::BadData),
+    let position = |codes: &[u8]| codes.iter().position(|code| *code == input);
+
+    let decoded = if let Some(key) = position(&inputs.keys) {
+        read_button_press_n1(device, input, key, state)
+    } else if let Some(encoder) = position(&inputs.encoder_ccw) {
+        read_encoder_value_n1(kind, input, encoder, -1)
+    } else if let Some(encoder) = position(&inputs.encoder_cw) {
+        read_encoder_value_n1(kind, input, encoder, 1)
+    } else if let Some(encoder) = position(&inputs.encoder_press) {
+        read_encoder_press_n1(device, encoder, state)
+    } else {
+        Err(MirajazzError::BadData)
     };
 
     if decoded.is_err() {
@@ -30,63 +81,112 @@ pub fn process_input_n1(input: u8, state: u8) -> Result<DeviceInput, MirajazzErr
     decoded
 }
 
-fn read_button_states(states: &[u8], key_count: usize) -> Vec<bool> {
-    let mut bools = vec![];
+fn read_button_press_n1(
+    device: &CandidateDevice,
+    input: u8,
+    key: usize,
+    state: u8,
+) -> Result<DeviceInput, MirajazzError> {
+    let mut devices = INPUT_STATES.lock().unwrap();
+    let states = devices.entry(device.id.clone()).or_default();
+    states.buttons.resize(device.kind.key_count(), false);
+    states.buttons[key] = state != 0;
+    let button_states = states.buttons.clone();
 
-    for i in 0..key_count {
-        bools.push(states[i + 1] != 0);
-    }
-
-    bools
-}
-
-fn read_button_press_n1(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
-    let mut button_states = vec![0x01];
-    button_states.extend(vec![0u8; KEY_COUNT_N1 + 1]);
-
-    let pressed_index: usize = match input {
-        0x01..=0x0f => input as usize, // Display keys 1..15
-        0x1e => 16,                    // Top button left
-        0x1f => 17,                    // Top button right
-        _ => return Err(MirajazzError::BadData),
-    };
-
-    button_states[pressed_index] = state;
     log::debug!(
         "Decoded N1 button raw=0x{input:02x} -> logical={} state={}",
-        pressed_index,
+        key,
         state
     );
 
-    Ok(DeviceInput::ButtonStateChange(read_button_states(
-        &button_states,
-        KEY_COUNT_N1,
-    )))
+    Ok(DeviceInput::ButtonStateChange(button_states))
 }
 
-fn read_encoder_value_n1(input: u8) -> Result<DeviceInput, MirajazzError> {
-    let mut encoder_values = vec![0i8; ENCODER_COUNT_N1];
-
-    let value: i8 = match input {
-        0x32 => -1,
-        0x33 => 1,
-        _ => return Err(MirajazzError::BadData),
-    };
+fn read_encoder_value_n1(
+    kind: &Kind,
+    input: u8,
+    encoder: usize,
+    value: i8,
+) -> Result<DeviceInput, MirajazzError> {
+    let mut encoder_values = vec![0i8; kind.encoder_count()];
+    encoder_values[encoder] = value;
 
-    encoder_values[0] = value;
     log::debug!(
-        "Decoded N1 encoder twist raw=0x{input:02x} -> encoder=0 delta={}",
+        "Decoded N1 encoder twist raw=0x{input:02x} -> encoder={} delta={}",
+        encoder,
         value
     );
     Ok(DeviceInput::EncoderTwist(encoder_values))
 }
 
-fn read_encoder_press_n1(state: u8) -> Result<DeviceInput, MirajazzError> {
-    let mut states = ENCODER_STATES.lock().unwrap();
-    states[0] = state == 0x01;
-    let encoder_states = states.to_vec();
-    drop(states);
-
-    log::debug!("N1 encoder states: {:#?}", encoder_states);
+fn read_encoder_press_n1(
+    device: &CandidateDevice,
+    encoder: usize,
+    state: u8,
+) -> Result<DeviceInput, MirajazzError> {
+    let mut devices = INPUT_STATES.lock().unwrap();
+    let states = devices.entry(device.id.clone()).or_default();
+    states.encoders.resize(device.kind.encoder_count(), false);
+    states.encoders[encoder] = state == 0x01;
+    let encoder_states = states.encoders.clone();
+
+    log::debug!("N1 encoder states for {}: {:#?}", device.id, encoder_states);
     Ok(DeviceInput::EncoderStateChange(encoder_states))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fake::fake_device_info, mappings::KINDS, watcher::device_info_to_candidate};
+
+    /// Raw input of the first keypad key and the encoder press
+    const KEY: u8 = 0x01;
+    const ENCODER_PRESS: u8 = 0x23;
+
+    fn candidate(serial: &str) -> CandidateDevice {
+        fake_device_info(serial, KINDS.first().unwrap())
+            .and_then(device_info_to_candidate)
+            .expect("fake devices are not supported on this platform")
+    }
+
+    fn buttons(device: &CandidateDevice, input: u8, state: u8) -> Vec<bool> {
+        match decode_input_n1(device, input, state) {
+            Ok(DeviceInput::ButtonStateChange(buttons)) => buttons,
+            other => panic!("expected button states, got {:?}", other),
+        }
+    }
+
+    fn encoders(device: &CandidateDevice, state: u8) -> Vec<bool> {
+        match decode_input_n1(device, ENCODER_PRESS, state) {
+            Ok(DeviceInput::EncoderStateChange(encoders)) => encoders,
+            other => panic!("expected encoder states, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn devices_keep_separate_state() {
+        let (a, b) = (candidate("inputs-a"), candidate("inputs-b"));
+
+        assert!(buttons(&a, KEY, 1)[0]);
+        assert!(encoders(&a, 1)[0]);
+
+        // Releasing the key on the other device leaves the first one held
+        assert!(!buttons(&b, KEY, 0)[0]);
+        assert!(!encoders(&b, 0)[0]);
+        assert!(buttons(&a, KEY + 1, 1)[0]);
+        assert!(encoders(&a, 1)[0]);
+    }
+
+    #[test]
+    fn forgetting_device_clears_only_its_state() {
+        let (a, b) = (candidate("forget-a"), candidate("forget-b"));
+
+        buttons(&a, KEY, 1);
+        buttons(&b, KEY, 1);
+        forget_device(&a.id);
+
+        // A reconnected device starts with every key up, the other one still has its key held
+        assert_eq!(buttons(&a, KEY + 1, 1)[..2], [false, true]);
+        assert_eq!(buttons(&b, KEY + 1, 1)[..2], [true, true]);
+    }
+}
@@ -1,7 +1,17 @@
+use config::{config, config_path};
 use device::{handle_error, handle_set_image};
-use mirajazz::device::Device;
+use hardware::Hardware;
+use mappings::KINDS;
 use openaction::*;
-use std::{collections::HashMap, env, process::exit, sync::LazyLock};
+use reload::reload_task;
+use restore::record_brightness;
+use std::{
+    collections::HashMap,
+    env,
+    path::PathBuf,
+    process::exit,
+    sync::{Arc, LazyLock},
+};
 use tokio::sync::{Mutex, RwLock};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use watcher::watcher_task;
@@ -9,17 +19,39 @@ use watcher::watcher_task;
 #[cfg(not(target_os = "windows"))]
 use tokio::signal::unix::{SignalKind, signal};
 
+mod animations;
+mod cache;
+mod capture;
+mod cli;
+mod config;
 mod device;
+mod encoders;
+mod errors;
+mod fake;
+mod gestures;
+mod hardware;
+mod images;
 mod inputs;
 mod mappings;
+mod monitor;
+mod queue;
+mod reload;
+mod restore;
+mod simulator;
+mod supervisor;
 mod watcher;
 
-pub static DEVICES: LazyLock<RwLock<HashMap<String, Device>>> =
+pub static DEVICES: LazyLock<RwLock<HashMap<String, Arc<dyn Hardware>>>> =
     LazyLock::new(|| RwLock::new(HashMap::new()));
 pub static TOKENS: LazyLock<RwLock<HashMap<String, CancellationToken>>> =
     LazyLock::new(|| RwLock::new(HashMap::new()));
 pub static TRACKER: LazyLock<Mutex<TaskTracker>> = LazyLock::new(|| Mutex::new(TaskTracker::new()));
 
+/// Directory the plugin binary lives in, where user-provided files are looked up
+pub fn plugin_dir() -> Option<PathBuf> {
+    Some(env::current_exe().ok()?.parent()?.to_path_buf())
+}
+
 struct GlobalEventHandler {}
 impl openaction::GlobalEventHandler for GlobalEventHandler {
     async fn plugin_ready(
@@ -36,6 +68,14 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
             .await
             .insert("_watcher_task".to_string(), token);
 
+        let token = CancellationToken::new();
+        tracker.spawn(reload_task(token.clone()));
+
+        TOKENS
+            .write()
+            .await
+            .insert("_reload_task".to_string(), token);
+
         log::info!("Plugin initialized");
 
         Ok(())
@@ -51,14 +91,10 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
 
         let id = event.device.clone();
 
-        if let Some(device) = DEVICES.read().await.get(&event.device) {
-            handle_set_image(device, event)
-                .await
-                .map_err(async |err| handle_error(&id, err).await)
-                .ok();
-        } else {
-            log::error!("Received event for unknown device: {}", event.device);
-        }
+        handle_set_image(event)
+            .await
+            .map_err(async |err| handle_error(&id, err).await)
+            .ok();
 
         Ok(())
     }
@@ -72,7 +108,11 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
 
         let id = event.device.clone();
 
-        if let Some(device) = DEVICES.read().await.get(&event.device) {
+        // Cloned out, so the map isn't locked while talking to the device
+        let device = DEVICES.read().await.get(&event.device).cloned();
+        if let Some(device) = device {
+            record_brightness(&id, event.brightness);
+
             device
                 .set_brightness(event.brightness)
                 .await
@@ -104,20 +144,6 @@ async fn connect() {
     }
 }
 
-fn parse_log_level() -> simplelog::LevelFilter {
-    let raw = env::var("OPENDECK_AKP05_LOG").unwrap_or_else(|_| "debug".to_string());
-
-    match raw.to_ascii_lowercase().as_str() {
-        "off" => simplelog::LevelFilter::Off,
-        "error" => simplelog::LevelFilter::Error,
-        "warn" | "warning" => simplelog::LevelFilter::Warn,
-        "info" => simplelog::LevelFilter::Info,
-        "debug" => simplelog::LevelFilter::Debug,
-        "trace" => simplelog::LevelFilter::Trace,
-        _ => simplelog::LevelFilter::Debug,
-    }
-}
-
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 async fn sigterm() -> Result<(), Box<dyn std::error::Error>> {
     let mut sig = signal(SignalKind::terminate())?;
@@ -138,23 +164,69 @@ async fn sigterm() -> Result<(), Box<dyn std::error::Error>> {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let log_level = parse_log_level();
-
+    // Logger lets everything through, the actual level is only known once config is loaded
     simplelog::TermLogger::init(
-        log_level,
+        simplelog::LevelFilter::Trace,
         simplelog::Config::default(),
         simplelog::TerminalMode::Stdout,
         simplelog::ColorChoice::Never,
     )
     .unwrap();
 
+    let log_level = config().log_level();
+    log::set_max_level(log_level);
+
+    let args: Vec<String> = env::args().collect();
+    match cli::parse_args(&args) {
+        Ok(None) => {}
+        Ok(Some(command)) => {
+            // Debug output of the plugin is too much for a terminal, unless asked for
+            if config().log_level.is_none() && env::var("OPENDECK_AKP05_LOG").is_err() {
+                log::set_max_level(simplelog::LevelFilter::Info);
+            }
+
+            if let Err(err) = cli::run(command).await {
+                log::error!("{}", err);
+                exit(1);
+            }
+
+            return Ok(());
+        }
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            exit(2);
+        }
+    }
+
     log::info!("Logger initialized with level {:?}", log_level);
     log::info!(
         "Plugin build version {} (with N1 mode+keepalive patches)",
         env!("CARGO_PKG_VERSION")
     );
-    log::info!("N1 startup mode: env OPENDECK_AKP05_N1_MODE (default: 3)");
+    match config_path() {
+        Some(path) => log::info!("Config file: {}", path.display()),
+        None => log::info!(
+            "No config file, create config.toml next to the plugin binary or in $XDG_CONFIG_HOME/opendeck-n1"
+        ),
+    }
+    log::info!(
+        "N1 startup mode: env OPENDECK_AKP05_N1_MODE (default: from config, then device profile)"
+    );
+    log::info!("Loaded {} device kinds", KINDS.len());
     log::info!("Set OPENDECK_AKP05_LOG=trace for maximum detail");
+    log::info!(
+        "Transparent images background: env OPENDECK_AKP05_IMAGE_BACKGROUND (default: #000000)"
+    );
+    log::info!(
+        "Image fit mode: env OPENDECK_AKP05_IMAGE_FIT stretch|contain|cover (default: stretch)"
+    );
+    log::info!(
+        "Animated GIF frame rate cap per device: env OPENDECK_AKP05_ANIMATION_FPS (default: from config, then 15)"
+    );
+    log::info!("Wide image spanning LCD segments: env OPENDECK_AKP05_LCD_SPAN=<position>");
+    log::info!("Fake devices fed from stdin: env OPENDECK_AKP05_FAKE_DEVICES=<serial>,...");
+    log::info!("Simulated screens of fake devices: env OPENDECK_AKP05_SIMULATOR_OUTPUT=<dir>|-");
+    log::info!("Raw input capture: env OPENDECK_AKP05_CAPTURE=<file>");
 
     tokio::select! {
         _ = connect() => {},
@@ -2,81 +2,594 @@ use mirajazz::{
     device::DeviceQuery,
     types::{HidDeviceInfo, ImageFormat, ImageMirroring, ImageMode, ImageRotation},
 };
+use serde::Deserialize;
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, LazyLock},
+};
+
+use crate::plugin_dir;
 
 // Must be unique between all the plugins, 2 characters long and match `DeviceNamespace` field in `manifest.json`
 pub const DEVICE_NAMESPACE: &str = "n1";
 
-#[derive(Debug, Clone)]
-pub enum Kind {
-    VsdInsideN1,
+/// Profiles compiled into the binary, so supported devices work out of the box
+const BUNDLED_PROFILES: &[(&str, &str)] = &[("n1.toml", include_str!("../profiles/n1.toml"))];
+
+/// Directory next to the plugin binary that is scanned for additional profiles
+const PROFILES_DIR: &str = "profiles";
+
+/// All device kinds known to the plugin, bundled ones first
+pub static KINDS: LazyLock<Vec<Kind>> = LazyLock::new(load_kinds);
+
+/// HID queries for every known kind
+pub static QUERIES: LazyLock<Vec<DeviceQuery>> =
+    LazyLock::new(|| KINDS.iter().map(|kind| kind.query()).collect());
+
+/// Describes a device model and its rebadges: how to find them, how they are laid out and how they talk
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Devices sharing this profile, usually rebadges of the same hardware
+    pub devices: Vec<DeviceId>,
+    #[serde(default = "default_usage_page")]
+    pub usage_page: u16,
+    #[serde(default = "default_usage_id")]
+    pub usage_id: u16,
+    pub protocol_version: usize,
+    pub device_type: u8,
+    /// Mode sent to the device right after connecting, if it needs one
+    #[serde(default)]
+    pub startup_mode: Option<u8>,
+    pub layout: Layout,
+    pub images: Images,
+    pub inputs: Inputs,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceId {
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    pub rows: usize,
+    pub cols: usize,
+    pub encoders: usize,
+    /// UI position reported to OpenDeck for every logical key, indexed by logical key
+    pub key_positions: Vec<u8>,
+    /// `[UI position, hardware image target]` pairs, UI positions not listed are input-only
+    pub image_targets: Vec<(u8, u8)>,
+    /// Hardware image targets that use the segment image format
+    #[serde(default)]
+    pub segment_targets: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Images {
+    pub key: ImageSpec,
+    /// Format of the secondary screen segments, defaults to the key format
+    #[serde(default)]
+    pub segment: Option<ImageSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageSpec {
+    pub size: (usize, usize),
+    #[serde(default)]
+    pub mode: Mode,
+    #[serde(default)]
+    pub rotation: Rotation,
+    #[serde(default)]
+    pub mirror: Mirror,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Jpeg,
+    Bmp,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "u16")]
+pub enum Rotation {
+    #[default]
+    Rot0,
+    Rot90,
+    Rot180,
+    Rot270,
+}
+
+impl TryFrom<u16> for Rotation {
+    type Error = String;
+
+    fn try_from(degrees: u16) -> Result<Self, Self::Error> {
+        match degrees {
+            0 => Ok(Rotation::Rot0),
+            90 => Ok(Rotation::Rot90),
+            180 => Ok(Rotation::Rot180),
+            270 => Ok(Rotation::Rot270),
+            _ => Err(format!(
+                "unsupported rotation {degrees}, expected 0/90/180/270"
+            )),
+        }
+    }
+}
+
+impl Rotation {
+    fn quarter_turns(self) -> u8 {
+        match self {
+            Rotation::Rot0 => 0,
+            Rotation::Rot90 => 1,
+            Rotation::Rot180 => 2,
+            Rotation::Rot270 => 3,
+        }
+    }
+
+    fn from_quarter_turns(turns: u8) -> Self {
+        match turns % 4 {
+            0 => Rotation::Rot0,
+            1 => Rotation::Rot90,
+            2 => Rotation::Rot180,
+            _ => Rotation::Rot270,
+        }
+    }
+
+    /// Returns true if width and height swap
+    fn is_sideways(self) -> bool {
+        self.quarter_turns() % 2 == 1
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mirror {
+    #[default]
+    None,
+    X,
+    Y,
+    Both,
 }
 
-pub const VSDINSIDE_VID: u16 = 0x5548;
-pub const N1_PID: u16 = 0x1002;
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Inputs {
+    /// Raw input code for every logical key, indexed by logical key
+    pub keys: Vec<u8>,
+    /// Raw input codes for every encoder, indexed by encoder
+    pub encoder_ccw: Vec<u8>,
+    pub encoder_cw: Vec<u8>,
+    pub encoder_press: Vec<u8>,
+    /// Non-input status frames as `[code, state]` pairs
+    #[serde(default)]
+    pub ignore: Vec<(u8, u8)>,
+}
 
-// Map all queries to usage page 65440 and usage id 1 for now
-pub const N1_QUERY: DeviceQuery = DeviceQuery::new(65440, 1, VSDINSIDE_VID, N1_PID);
+fn default_usage_page() -> u16 {
+    65440
+}
 
-pub const QUERIES: &[DeviceQuery] = &[N1_QUERY];
+fn default_usage_id() -> u16 {
+    1
+}
+
+impl ImageSpec {
+    /// Returns format for the device, with images turned by `extra` on top of the profile rotation
+    fn to_format(&self, extra: Rotation) -> ImageFormat {
+        let rotation =
+            Rotation::from_quarter_turns(self.rotation.quarter_turns() + extra.quarter_turns());
+
+        ImageFormat {
+            mode: match self.mode {
+                Mode::Jpeg => ImageMode::JPEG,
+                Mode::Bmp => ImageMode::BMP,
+            },
+            size: self.size,
+            rotation: match rotation {
+                Rotation::Rot0 => ImageRotation::Rot0,
+                Rotation::Rot90 => ImageRotation::Rot90,
+                Rotation::Rot180 => ImageRotation::Rot180,
+                Rotation::Rot270 => ImageRotation::Rot270,
+            },
+            mirror: match self.mirror {
+                Mirror::None => ImageMirroring::None,
+                Mirror::X => ImageMirroring::X,
+                Mirror::Y => ImageMirroring::Y,
+                Mirror::Both => ImageMirroring::Both,
+            },
+        }
+    }
+}
+
+impl Profile {
+    /// Checks that the tables of the profile are consistent with each other
+    fn validate(&self) -> Result<(), String> {
+        let layout = &self.layout;
+        let inputs = &self.inputs;
+        let positions = layout.rows * layout.cols;
+
+        if self.devices.is_empty() {
+            return Err("profile has no devices".to_string());
+        }
+
+        for (index, device) in self.devices.iter().enumerate() {
+            if self.devices[..index].iter().any(|other| {
+                (other.vendor_id, other.product_id) == (device.vendor_id, device.product_id)
+            }) {
+                return Err(format!(
+                    "{:04x}:{:04x} of {} is listed twice",
+                    device.vendor_id, device.product_id, device.name
+                ));
+            }
+        }
+
+        if positions == 0 {
+            return Err(format!(
+                "{}x{} grid has no positions",
+                layout.rows, layout.cols
+            ));
+        }
+
+        // UI positions are sent to OpenDeck as u8
+        if positions > u8::MAX as usize + 1 {
+            return Err(format!(
+                "{}x{} grid has more than 256 positions",
+                layout.rows, layout.cols
+            ));
+        }
+
+        if inputs.keys.len() != layout.key_positions.len() {
+            return Err(format!(
+                "inputs.keys has {} entries, layout.key_positions has {}",
+                inputs.keys.len(),
+                layout.key_positions.len()
+            ));
+        }
+
+        for (name, codes) in [
+            ("encoder_ccw", &inputs.encoder_ccw),
+            ("encoder_cw", &inputs.encoder_cw),
+            ("encoder_press", &inputs.encoder_press),
+        ] {
+            if codes.len() != layout.encoders {
+                return Err(format!(
+                    "inputs.{} has {} entries, expected {} encoders",
+                    name,
+                    codes.len(),
+                    layout.encoders
+                ));
+            }
+        }
+
+        if let Some(position) = layout
+            .key_positions
+            .iter()
+            .chain(layout.image_targets.iter().map(|(ui, _)| ui))
+            .find(|position| **position as usize >= positions)
+        {
+            return Err(format!(
+                "UI position {} is outside of the {}x{} grid",
+                position, layout.rows, layout.cols
+            ));
+        }
+
+        for (index, (ui, hw)) in layout.image_targets.iter().enumerate() {
+            if layout.image_targets[..index]
+                .iter()
+                .any(|(other_ui, other_hw)| other_ui == ui || other_hw == hw)
+            {
+                return Err(format!(
+                    "image target [{}, {}] collides with another entry",
+                    ui, hw
+                ));
+            }
+        }
+
+        if let Some(segment) = layout
+            .segment_targets
+            .iter()
+            .find(|segment| !layout.image_targets.iter().any(|(_, hw)| hw == *segment))
+        {
+            return Err(format!(
+                "segment target {} is not one of the image targets",
+                segment
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn read_profile(contents: &str) -> Result<Profile, String> {
+    let profile = toml::from_str::<Profile>(contents).map_err(|err| err.to_string())?;
+    profile.validate()?;
+
+    Ok(profile)
+}
+
+fn parse_profile(source: &str, contents: &str) -> Option<Profile> {
+    read_profile(contents)
+        .inspect_err(|err| log::error!("Invalid device profile {}: {}", source, err))
+        .ok()
+}
+
+/// Loads bundled profiles and then profiles from the plugin directory.
+/// A device in a later profile replaces an earlier device with the same VID+PID.
+fn load_kinds() -> Vec<Kind> {
+    let mut kinds: Vec<Kind> = Vec::new();
+
+    let mut add = |source: &str, contents: &str| {
+        let Some(profile) = parse_profile(source, contents) else {
+            return;
+        };
+        let profile = Arc::new(profile);
+
+        for index in 0..profile.devices.len() {
+            let kind = Kind {
+                profile: profile.clone(),
+                index,
+                orientation: Rotation::Rot0,
+            };
+
+            log::info!(
+                "Loaded device {} ({:04x}:{:04x}) from profile {}",
+                kind.human_name(),
+                kind.device().vendor_id,
+                kind.device().product_id,
+                source
+            );
+
+            kinds.retain(|existing| existing.vid_pid() != kind.vid_pid());
+            kinds.push(kind);
+        }
+    };
+
+    for (name, contents) in BUNDLED_PROFILES {
+        add(name, contents);
+    }
+
+    if let Some(dir) = plugin_dir().map(|dir| dir.join(PROFILES_DIR)) {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+                    .collect()
+            })
+            .unwrap_or_default();
+        paths.sort();
+
+        for path in paths {
+            match fs::read_to_string(&path) {
+                Ok(contents) => add(&path.display().to_string(), &contents),
+                Err(err) => log::error!("Failed to read {}: {}", path.display(), err),
+            }
+        }
+    }
+
+    kinds
+}
+
+/// A kind of device: one of the devices of a profile, as mounted.
+/// Grid, positions and image formats are all as seen in the mounted orientation
+#[derive(Debug, Clone)]
+pub struct Kind {
+    profile: Arc<Profile>,
+    index: usize,
+    orientation: Rotation,
+}
 
 impl Kind {
     /// Matches devices VID+PID pairs to correct kinds
     pub fn from_vid_pid(vid: u16, pid: u16) -> Option<Self> {
-        if vid == VSDINSIDE_VID && pid == N1_PID {
-            Some(Kind::VsdInsideN1)
-        } else {
-            None
-        }
+        KINDS
+            .iter()
+            .find(|kind| kind.vid_pid() == (vid, pid))
+            .cloned()
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    fn device(&self) -> &DeviceId {
+        &self.profile.devices[self.index]
+    }
+
+    pub fn vid_pid(&self) -> (u16, u16) {
+        (self.device().vendor_id, self.device().product_id)
+    }
+
+    fn query(&self) -> DeviceQuery {
+        DeviceQuery::new(
+            self.profile.usage_page,
+            self.profile.usage_id,
+            self.device().vendor_id,
+            self.device().product_id,
+        )
     }
 
     /// There is no point relying on manufacturer/device names reported by the USB stack,
     /// so we return custom names for all the kinds of devices
     pub fn human_name(&self) -> String {
-        "VSD Inside N1".to_string()
+        self.device().name.clone()
     }
 
     /// Returns protocol version for device
     pub fn protocol_version(&self) -> usize {
-        3
+        self.profile.protocol_version
+    }
+
+    pub fn startup_mode(&self) -> Option<u8> {
+        self.profile.startup_mode
+    }
+
+    /// Returns the same kind mounted rotated clockwise by `orientation`
+    pub fn oriented(&self, orientation: Rotation) -> Kind {
+        Kind {
+            orientation,
+            ..self.clone()
+        }
+    }
+
+    pub fn orientation(&self) -> Rotation {
+        self.orientation
     }
 
     pub fn row_count(&self) -> usize {
-        7
+        if self.orientation.is_sideways() {
+            self.profile.layout.cols
+        } else {
+            self.profile.layout.rows
+        }
     }
 
     pub fn col_count(&self) -> usize {
-        3
+        if self.orientation.is_sideways() {
+            self.profile.layout.rows
+        } else {
+            self.profile.layout.cols
+        }
+    }
+
+    /// Maps position in the profile grid to the grid as mounted
+    fn rotate_position(&self, position: u8) -> u8 {
+        let (rows, cols) = (self.profile.layout.rows, self.profile.layout.cols);
+        let (row, col) = (position as usize / cols, position as usize % cols);
+
+        let (row, col) = match self.orientation {
+            Rotation::Rot0 => (row, col),
+            Rotation::Rot90 => (col, rows - 1 - row),
+            Rotation::Rot180 => (rows - 1 - row, cols - 1 - col),
+            Rotation::Rot270 => (cols - 1 - col, row),
+        };
+
+        (row * self.col_count() + col) as u8
+    }
+
+    /// Maps position in the grid as mounted back to the profile grid
+    fn unrotate_position(&self, position: u8) -> u8 {
+        let (rows, cols) = (self.profile.layout.rows, self.profile.layout.cols);
+        let (row, col) = (
+            position as usize / self.col_count(),
+            position as usize % self.col_count(),
+        );
+
+        let (row, col) = match self.orientation {
+            Rotation::Rot0 => (row, col),
+            Rotation::Rot90 => (rows - 1 - col, row),
+            Rotation::Rot180 => (rows - 1 - row, cols - 1 - col),
+            Rotation::Rot270 => (col, cols - 1 - row),
+        };
+
+        (row * cols + col) as u8
     }
 
     pub fn key_count(&self) -> usize {
-        17
+        self.profile.inputs.keys.len()
     }
 
     pub fn encoder_count(&self) -> usize {
-        1
+        self.profile.layout.encoders
     }
 
     pub fn device_type(&self) -> u8 {
-        7 // StreamDeckPlus
+        self.profile.device_type
+    }
+
+    /// Images are turned back against the mounting, so they end up upright
+    fn counter_rotation(&self) -> Rotation {
+        Rotation::from_quarter_turns(4 - self.orientation.quarter_turns())
     }
 
     pub fn image_format(&self) -> ImageFormat {
-        ImageFormat {
-            mode: ImageMode::JPEG,
-            size: (96, 96),
-            rotation: ImageRotation::Rot0,
-            mirror: ImageMirroring::None,
-        }
+        self.profile.images.key.to_format(self.counter_rotation())
     }
+
     pub fn touch_image_format(&self) -> ImageFormat {
-        ImageFormat {
-            mode: ImageMode::JPEG,
-            // N1 second screen segments are 64x64 each.
-            size: (64, 64),
-            rotation: ImageRotation::Rot0,
-            mirror: ImageMirroring::None,
+        self.profile
+            .images
+            .segment
+            .as_ref()
+            .unwrap_or(&self.profile.images.key)
+            .to_format(self.counter_rotation())
+    }
+
+    /// Returns size images for a hardware image target have as seen when mounted
+    pub fn image_size_for(&self, hw_pos: u8) -> (usize, usize) {
+        let (width, height) = self.image_format_for(hw_pos).size;
+
+        if self.orientation.is_sideways() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// Returns image format for a hardware image target
+    pub fn image_format_for(&self, hw_pos: u8) -> ImageFormat {
+        if self.profile.layout.segment_targets.contains(&hw_pos) {
+            self.touch_image_format()
+        } else {
+            self.image_format()
         }
     }
+
+    /// All hardware image targets of the device
+    pub fn hw_image_targets(&self) -> impl Iterator<Item = u8> + '_ {
+        self.profile.layout.image_targets.iter().map(|(_, hw)| *hw)
+    }
+
+    /// Hardware targets of the screen segments, left to right or top to bottom as mounted
+    pub fn segment_targets(&self) -> Vec<u8> {
+        let mut segments = self.profile.layout.segment_targets.clone();
+
+        if matches!(self.orientation, Rotation::Rot180 | Rotation::Rot270) {
+            segments.reverse();
+        }
+
+        segments
+    }
+
+    /// Returns true if the screen segments form a column as mounted
+    pub fn segments_vertical(&self) -> bool {
+        self.orientation.is_sideways()
+    }
+
+    /// Maps a logical key reported by the device to UI position
+    pub fn input_key_to_ui(&self, key: u8) -> Option<u8> {
+        let position = self.profile.layout.key_positions.get(key as usize)?;
+
+        Some(self.rotate_position(*position))
+    }
+
+    /// Maps UI position to hardware image target, `None` for input-only positions.
+    /// Positions outside of the grid are not valid
+    pub fn image_position_to_hw(&self, position: u8) -> Option<Option<u8>> {
+        if position as usize >= self.row_count() * self.col_count() {
+            return None;
+        }
+
+        let position = self.unrotate_position(position);
+
+        Some(
+            self.profile
+                .layout
+                .image_targets
+                .iter()
+                .find(|(ui, _)| *ui == position)
+                .map(|(_, hw)| *hw),
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -85,3 +598,233 @@ pub struct CandidateDevice {
     pub dev: HidDeviceInfo,
     pub kind: Kind,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Smallest valid profile: a 2x3 grid with a segment row on top
+    const MINIMAL: &str = r#"
+        protocol_version = 3
+        device_type = 7
+
+        [[devices]]
+        name = "First"
+        vendor_id = 0x1234
+        product_id = 0x0001
+
+        [[devices]]
+        name = "Second"
+        vendor_id = 0x1234
+        product_id = 0x0002
+
+        [layout]
+        rows = 2
+        cols = 3
+        encoders = 0
+        key_positions = [3, 4, 5]
+        image_targets = [[0, 10], [1, 11], [2, 12], [3, 0], [4, 1], [5, 2]]
+        segment_targets = [10, 11, 12]
+
+        [images.key]
+        size = [96, 96]
+
+        [inputs]
+        keys = [1, 2, 3]
+        encoder_ccw = []
+        encoder_cw = []
+        encoder_press = []
+    "#;
+
+    #[test]
+    fn bundled_profiles_are_valid() {
+        for (name, contents) in BUNDLED_PROFILES {
+            if let Err(err) = read_profile(contents) {
+                panic!("{} is invalid: {}", name, err);
+            }
+        }
+
+        let n1 = read_profile(BUNDLED_PROFILES[0].1).unwrap();
+        assert_eq!((n1.layout.rows, n1.layout.cols), (7, 3));
+        assert_eq!(n1.devices.len(), 3);
+        assert_eq!(n1.inputs.keys.len(), 17);
+        assert_eq!(n1.layout.segment_targets, [15, 16, 17]);
+    }
+
+    #[test]
+    fn accepts_minimal_profile() {
+        assert!(read_profile(MINIMAL).is_ok());
+    }
+
+    #[test]
+    fn rejects_duplicate_devices() {
+        let profile = MINIMAL.replace("product_id = 0x0002", "product_id = 0x0001");
+        let err = read_profile(&profile).unwrap_err();
+        assert!(err.contains("listed twice"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_empty_grid() {
+        for layout in ["rows = 0\n        cols = 3", "rows = 2\n        cols = 0"] {
+            let profile = MINIMAL.replace("rows = 2\n        cols = 3", layout);
+            let err = read_profile(&profile).unwrap_err();
+            assert!(err.contains("no positions"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn rejects_segment_targets_outside_image_targets() {
+        let profile = MINIMAL.replace("segment_targets = [10, 11, 12]", "segment_targets = [13]");
+        let err = read_profile(&profile).unwrap_err();
+        assert!(err.contains("segment target 13"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_positions_outside_grid() {
+        let profile = MINIMAL.replace("key_positions = [3, 4, 5]", "key_positions = [3, 4, 6]");
+        let err = read_profile(&profile).unwrap_err();
+        assert!(err.contains("UI position 6"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_unsupported_rotation() {
+        for rotation in [0, 90, 180, 270] {
+            let profile = MINIMAL.replace(
+                "size = [96, 96]",
+                &format!("size = [96, 96]\nrotation = {}", rotation),
+            );
+            assert!(read_profile(&profile).is_ok(), "{} is rejected", rotation);
+        }
+
+        for rotation in [45, 360, -90] {
+            let profile = MINIMAL.replace(
+                "size = [96, 96]",
+                &format!("size = [96, 96]\nrotation = {}", rotation),
+            );
+            assert!(read_profile(&profile).is_err(), "{} is accepted", rotation);
+        }
+    }
+
+    const ORIENTATIONS: [Rotation; 4] = [
+        Rotation::Rot0,
+        Rotation::Rot90,
+        Rotation::Rot180,
+        Rotation::Rot270,
+    ];
+
+    fn kind(profile: &str, orientation: Rotation) -> Kind {
+        Kind {
+            profile: Arc::new(read_profile(profile).unwrap()),
+            index: 0,
+            orientation,
+        }
+    }
+
+    /// A 3x6 grid, wide enough to tell rows and columns apart in every orientation
+    fn wide(orientation: Rotation) -> Kind {
+        kind(
+            &MINIMAL.replace("rows = 2\n        cols = 3", "rows = 3\n        cols = 6"),
+            orientation,
+        )
+    }
+
+    #[test]
+    fn grid_turns_with_orientation() {
+        // Profile positions shown on every position as mounted, row by row
+        let expected: [(Rotation, (usize, usize), [u8; 18]); 4] = [
+            (
+                Rotation::Rot0,
+                (3, 6),
+                [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17],
+            ),
+            (
+                Rotation::Rot90,
+                (6, 3),
+                [12, 6, 0, 13, 7, 1, 14, 8, 2, 15, 9, 3, 16, 10, 4, 17, 11, 5],
+            ),
+            (
+                Rotation::Rot180,
+                (3, 6),
+                [17, 16, 15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0],
+            ),
+            (
+                Rotation::Rot270,
+                (6, 3),
+                [5, 11, 17, 4, 10, 16, 3, 9, 15, 2, 8, 14, 1, 7, 13, 0, 6, 12],
+            ),
+        ];
+
+        for (orientation, grid, positions) in expected {
+            let kind = wide(orientation);
+            assert_eq!((kind.row_count(), kind.col_count()), grid);
+
+            for (mounted, position) in positions.into_iter().enumerate() {
+                let mounted = mounted as u8;
+                assert_eq!(
+                    kind.unrotate_position(mounted),
+                    position,
+                    "{:?}",
+                    orientation
+                );
+                assert_eq!(kind.rotate_position(position), mounted, "{:?}", orientation);
+            }
+        }
+    }
+
+    #[test]
+    fn unrotating_undoes_rotating() {
+        for orientation in ORIENTATIONS {
+            let wide = wide(orientation);
+            for position in 0..18 {
+                assert_eq!(
+                    wide.unrotate_position(wide.rotate_position(position)),
+                    position
+                );
+            }
+
+            let n1 = KINDS.first().unwrap().oriented(orientation);
+            for position in 0..21 {
+                assert_eq!(n1.unrotate_position(n1.rotate_position(position)), position);
+                assert_eq!(n1.rotate_position(n1.unrotate_position(position)), position);
+            }
+        }
+    }
+
+    #[test]
+    fn segments_are_reversed_when_upside_down() {
+        let n1 = KINDS.first().unwrap();
+
+        assert_eq!(n1.oriented(Rotation::Rot0).segment_targets(), [15, 16, 17]);
+        assert_eq!(n1.oriented(Rotation::Rot90).segment_targets(), [15, 16, 17]);
+        assert_eq!(
+            n1.oriented(Rotation::Rot180).segment_targets(),
+            [17, 16, 15]
+        );
+        assert_eq!(
+            n1.oriented(Rotation::Rot270).segment_targets(),
+            [17, 16, 15]
+        );
+    }
+
+    #[test]
+    fn image_rotation_adds_up_with_profile_rotation() {
+        let profile = MINIMAL.replace("size = [96, 96]", "size = [96, 96]\nrotation = 90");
+
+        // Images are turned against the mounting, on top of the 90 degrees of the profile
+        let expected = [
+            (Rotation::Rot0, "Rot90"),
+            (Rotation::Rot90, "Rot0"),
+            (Rotation::Rot180, "Rot270"),
+            (Rotation::Rot270, "Rot180"),
+        ];
+        for (orientation, rotation) in expected {
+            let kind = kind(&profile, orientation);
+            assert_eq!(format!("{:?}", kind.image_format().rotation), rotation);
+            // Segments have no image settings of their own, so they follow the keys
+            assert_eq!(
+                format!("{:?}", kind.touch_image_format().rotation),
+                rotation
+            );
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::{LazyLock, Mutex};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{encoders::EncoderEvent, gestures::KeyEvent};
+
+/// Input event of a device that was not sent to OpenDeck
+#[derive(Debug)]
+pub enum InputEvent {
+    Key(String, KeyEvent),
+    Encoder(String, EncoderEvent),
+}
+
+/// Receives input events while nothing is connected to OpenDeck, set by the `monitor` command
+static MONITOR: LazyLock<Mutex<Option<UnboundedSender<InputEvent>>>> =
+    LazyLock::new(|| Mutex::new(None));
+
+/// Sends input events that can't go to OpenDeck to `sender` from now on
+pub fn set_monitor(sender: UnboundedSender<InputEvent>) {
+    *MONITOR.lock().unwrap() = Some(sender);
+}
+
+/// Hands an input event to the monitor, returning false if there is none listening
+pub fn monitor(event: InputEvent) -> bool {
+    MONITOR
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|sender| sender.send(event).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn events_reach_the_monitor_while_it_listens() {
+        let (sender, mut events) = unbounded_channel();
+        set_monitor(sender);
+
+        assert!(monitor(InputEvent::Key(
+            "monitored".to_string(),
+            KeyEvent::Down(6)
+        )));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(InputEvent::Key(id, KeyEvent::Down(6))) if id == "monitored"
+        ));
+
+        // Nobody is listening once the receiver is gone, so the caller logs the event instead
+        drop(events);
+        assert!(!monitor(InputEvent::Encoder(
+            "monitored".to_string(),
+            EncoderEvent::Twist(0, 1)
+        )));
+    }
+}
@@ -0,0 +1,248 @@
+use image::DynamicImage;
+use mirajazz::{error::MirajazzError, types::ImageFormat};
+use std::{collections::HashMap, sync::LazyLock};
+use tokio::{
+    sync::{
+        RwLock,
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+    },
+    time::{Duration, Instant, sleep_until},
+};
+
+use crate::{
+    DEVICES,
+    cache::{Shown, is_shown, record_all_cleared, record_shown},
+    device::handle_error,
+    mappings::{CandidateDevice, Kind},
+};
+
+/// How long to wait for more commands after the last one before flushing
+const COALESCE_WINDOW: Duration = Duration::from_millis(5);
+/// Longest a command can wait in the queue, so a steady stream of images still gets shown
+const MAX_BATCH_DELAY: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum ImageCommand {
+    Set {
+        hw_pos: u8,
+        format: ImageFormat,
+        image: DynamicImage,
+    },
+    Clear(u8),
+    ClearAll,
+}
+
+impl ImageCommand {
+    fn hw_pos(&self) -> Option<u8> {
+        match self {
+            ImageCommand::Set { hw_pos, .. } | ImageCommand::Clear(hw_pos) => Some(*hw_pos),
+            ImageCommand::ClearAll => None,
+        }
+    }
+}
+
+struct ImageQueue {
+    kind: Kind,
+    sender: UnboundedSender<ImageCommand>,
+}
+
+/// Image queues of all running devices, keyed by device id
+static IMAGE_QUEUES: LazyLock<RwLock<HashMap<String, ImageQueue>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Returns kind of a device and the queue to send its image commands to
+pub async fn image_queue(id: &str) -> Option<(Kind, UnboundedSender<ImageCommand>)> {
+    IMAGE_QUEUES
+        .read()
+        .await
+        .get(id)
+        .map(|queue| (queue.kind.clone(), queue.sender.clone()))
+}
+
+pub async fn forget_image_queue(id: &str) {
+    IMAGE_QUEUES.write().await.remove(id);
+}
+
+/// Adds a command to the batch, dropping commands it makes pointless
+fn coalesce(batch: &mut Vec<ImageCommand>, command: ImageCommand) {
+    match command.hw_pos() {
+        Some(hw_pos) => batch.retain(|queued| queued.hw_pos() != Some(hw_pos)),
+        None => batch.clear(),
+    }
+
+    batch.push(command);
+}
+
+/// Creates image queue of a device
+pub async fn register_image_queue(candidate: &CandidateDevice) -> UnboundedReceiver<ImageCommand> {
+    let (sender, commands) = mpsc::unbounded_channel();
+
+    IMAGE_QUEUES.write().await.insert(
+        candidate.id.clone(),
+        ImageQueue {
+            kind: candidate.kind.clone(),
+            sender,
+        },
+    );
+
+    commands
+}
+
+/// Collects image commands of a device arriving close to each other and applies them with a single flush
+pub async fn image_queue_task(
+    candidate: &CandidateDevice,
+    mut commands: UnboundedReceiver<ImageCommand>,
+) {
+    while let Some(first) = commands.recv().await {
+        let started = Instant::now();
+        let mut batch = vec![first];
+        let mut last = started;
+
+        loop {
+            let deadline = (last + COALESCE_WINDOW).min(started + MAX_BATCH_DELAY);
+
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => {
+                        coalesce(&mut batch, command);
+                        last = Instant::now();
+                    }
+                    None => break,
+                },
+                _ = sleep_until(deadline) => break,
+            }
+        }
+
+        log::debug!(
+            "Applying {} image commands for {} after {:?}",
+            batch.len(),
+            candidate.id,
+            started.elapsed()
+        );
+
+        if let Err(err) = apply(candidate, batch).await
+            && !handle_error(&candidate.id, err).await
+        {
+            break;
+        }
+    }
+}
+
+async fn apply(candidate: &CandidateDevice, batch: Vec<ImageCommand>) -> Result<(), MirajazzError> {
+    let id = &candidate.id;
+    // Cloned out, so other tasks can reach the device map during slow uploads
+    let device = DEVICES.read().await.get(id).cloned();
+    let Some(device) = device else {
+        return Ok(());
+    };
+
+    let mut changed = false;
+
+    for command in batch {
+        match command {
+            ImageCommand::Set {
+                hw_pos,
+                format,
+                image,
+            } => {
+                // OpenDeck resends whole profiles on page switches, no need to send what is already shown
+                let shown = Shown::image(&image);
+                if is_shown(id, hw_pos, shown) {
+                    log::debug!("Skipping unchanged image at hw_button={}", hw_pos);
+                    continue;
+                }
+
+                device.set_button_image(hw_pos, format, image).await?;
+                record_shown(id, hw_pos, shown);
+            }
+            ImageCommand::Clear(hw_pos) => {
+                if is_shown(id, hw_pos, Shown::Cleared) {
+                    log::debug!("Skipping clear of already clear hw_button={}", hw_pos);
+                    continue;
+                }
+
+                device.clear_button_image(hw_pos).await?;
+                record_shown(id, hw_pos, Shown::Cleared);
+            }
+            ImageCommand::ClearAll => {
+                device.clear_all_button_images().await?;
+                record_all_cleared(id, candidate.kind.hw_image_targets());
+            }
+        }
+
+        changed = true;
+    }
+
+    if changed {
+        device.flush().await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappings::KINDS;
+
+    fn set(hw_pos: u8, width: u32) -> ImageCommand {
+        let kind = KINDS.first().unwrap();
+
+        ImageCommand::Set {
+            hw_pos,
+            format: kind.image_format_for(hw_pos),
+            image: DynamicImage::new_rgb8(width, 1),
+        }
+    }
+
+    fn batch(commands: impl IntoIterator<Item = ImageCommand>) -> Vec<String> {
+        let mut batch = Vec::new();
+        for command in commands {
+            coalesce(&mut batch, command);
+        }
+
+        batch
+            .iter()
+            .map(|command| match command {
+                ImageCommand::Set { hw_pos, image, .. } => {
+                    format!("Set {} {}", hw_pos, image.width())
+                }
+                ImageCommand::Clear(hw_pos) => format!("Clear {}", hw_pos),
+                ImageCommand::ClearAll => "ClearAll".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn later_image_replaces_earlier_one() {
+        assert_eq!(
+            batch([set(0, 1), set(1, 1), set(0, 2)]),
+            ["Set 1 1", "Set 0 2"]
+        );
+    }
+
+    #[test]
+    fn clear_drops_queued_images() {
+        assert_eq!(
+            batch([set(0, 1), set(1, 1), ImageCommand::Clear(0)]),
+            ["Set 1 1", "Clear 0"]
+        );
+        assert_eq!(
+            batch([set(0, 1), ImageCommand::Clear(2), ImageCommand::ClearAll]),
+            ["ClearAll"]
+        );
+        // Images after clearing everything still have to be shown
+        assert_eq!(
+            batch([ImageCommand::ClearAll, set(0, 1)]),
+            ["ClearAll", "Set 0 1"]
+        );
+    }
+
+    #[test]
+    fn commands_for_different_keys_keep_their_order() {
+        assert_eq!(
+            batch([set(2, 1), ImageCommand::Clear(0), set(1, 1), set(3, 1)]),
+            ["Set 2 1", "Clear 0", "Set 1 1", "Set 3 1"]
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use openaction::OUTBOUND_EVENT_MANAGER;
+use std::{fs, path::PathBuf, sync::Arc, time::SystemTime};
+use tokio::time::{Duration, sleep};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    DEVICES,
+    animations::stop_animations,
+    config::{Config, config_path, reload_config},
+    device::handle_error,
+    hardware::Hardware,
+    mappings::Kind,
+    queue::{ImageCommand, image_queue},
+    restore::{record_brightness, restore_images},
+    supervisor::cancel_session,
+    watcher::id_to_serial,
+};
+
+/// How often the config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Identifies a version of a file without reading it
+type Fingerprint = Option<(PathBuf, SystemTime, u64)>;
+
+fn fingerprint(path: Option<PathBuf>) -> Fingerprint {
+    let path = path?;
+    let metadata = fs::metadata(&path).ok()?;
+
+    Some((path, metadata.modified().ok()?, metadata.len()))
+}
+
+/// Watches the config file and applies its changes to running devices
+pub async fn reload_task(token: CancellationToken) {
+    let mut config = fingerprint(config_path());
+
+    loop {
+        tokio::select! {
+            _ = sleep(POLL_INTERVAL) => {},
+            _ = token.cancelled() => break,
+        }
+
+        let current = fingerprint(config_path());
+        if current != config {
+            config = current;
+
+            if let Some((previous, config)) = reload_config() {
+                apply_config(&previous, &config).await;
+            }
+        }
+    }
+}
+
+/// Ends the session of a device, so it reconnects and registers with its new grid
+async fn register_again(id: &str) {
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+        outbound.deregister_device(id.to_string()).await.ok();
+    }
+    cancel_session(id).await;
+}
+
+/// Applies settings that changed to every connected device, without re-registering them
+async fn apply_config(previous: &Config, config: &Config) {
+    log::set_max_level(config.log_level());
+
+    let mut redrawn: Vec<String> = Vec::new();
+    let mut reoriented: Vec<String> = Vec::new();
+
+    // Cloned out, so talking to the devices doesn't hold up others waiting for the device list
+    let devices: Vec<(String, Arc<dyn Hardware>)> = DEVICES
+        .read()
+        .await
+        .iter()
+        .map(|(id, device)| (id.clone(), device.clone()))
+        .collect();
+
+    for (id, device) in devices {
+        let serial = id_to_serial(&id);
+        let before = previous.for_serial(serial);
+        let after = config.for_serial(serial);
+
+        if after == before {
+            continue;
+        }
+
+        log::info!("Applying new settings to {}: {:?}", id, after);
+
+        if after.mode != before.mode {
+            // Without a configured mode the device goes back to the one from its profile
+            let mode = after.mode.or_else(|| {
+                let (vid, pid) = device.vid_pid();
+                Kind::from_vid_pid(vid, pid).and_then(|kind| kind.startup_mode())
+            });
+
+            if let Some(mode) = mode
+                && let Err(err) = device.set_mode(mode).await
+            {
+                handle_error(&id, err).await;
+                continue;
+            }
+        }
+
+        // Grid size is only told to OpenDeck when registering, so this takes a new session
+        if after.orientation != before.orientation
+            || after.gestures.virtual_rows != before.gestures.virtual_rows
+        {
+            reoriented.push(id);
+            continue;
+        }
+
+        // Running animations keep the frame rate they started with
+        if after.remap != before.remap
+            || after.layout != before.layout
+            || after.animation_fps != before.animation_fps
+        {
+            redrawn.push(id.clone());
+        }
+
+        if after.brightness != before.brightness {
+            if let Err(err) = device.set_brightness(after.brightness).await {
+                handle_error(&id, err).await;
+                continue;
+            }
+
+            record_brightness(&id, after.brightness);
+        }
+    }
+
+    for id in reoriented {
+        log::info!("Grid of {} changed, registering it again", id);
+        register_again(&id).await;
+    }
+
+    // Images are set again, following their keys to new positions and fitted to the new layout
+    for id in redrawn {
+        let Some((_, queue)) = image_queue(&id).await else {
+            continue;
+        };
+
+        log::info!(
+            "Key remap or layout of {} changed, setting images again",
+            id
+        );
+        stop_animations(&id);
+        queue.send(ImageCommand::ClearAll).ok();
+        restore_images(&id).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fake::{FakeDevice, FakeHandle, fake_device, fake_device_info},
+        mappings::{CandidateDevice, KINDS},
+        queue::register_image_queue,
+        supervisor::begin_session,
+        watcher::device_info_to_candidate,
+    };
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    /// Connected fake device, the way a running session leaves it
+    struct Connected {
+        candidate: CandidateDevice,
+        device: FakeHandle,
+        images: UnboundedReceiver<ImageCommand>,
+        token: CancellationToken,
+    }
+
+    impl Connected {
+        async fn new(serial: &str) -> Self {
+            let candidate = fake_device_info(serial, KINDS.first().unwrap())
+                .and_then(device_info_to_candidate)
+                .expect("fake devices are not supported on this platform");
+            let device = FakeDevice::connect(&candidate.dev, 3, 17, 1).await.unwrap();
+            DEVICES
+                .write()
+                .await
+                .insert(candidate.id.clone(), Arc::new(device));
+
+            let images = register_image_queue(&candidate).await;
+            let token = CancellationToken::new();
+            begin_session(&candidate.id, token.clone()).await;
+
+            Self {
+                device: fake_device(serial).unwrap(),
+                candidate,
+                images,
+                token,
+            }
+        }
+
+        async fn disconnect(self) {
+            DEVICES.write().await.remove(&self.candidate.id);
+        }
+    }
+
+    fn parse(config: &str) -> Config {
+        toml::from_str(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn grid_change_registers_device_again() {
+        let mut reoriented = Connected::new("reload-orientation").await;
+        let mut rows = Connected::new("reload-rows").await;
+
+        let config = parse(
+            r#"
+            [devices.reload-orientation]
+            orientation = 90
+            remap = [[6, 7], [7, 6]]
+
+            [devices.reload-rows.gestures]
+            virtual_rows = 1
+            "#,
+        );
+        apply_config(&Config::default(), &config).await;
+
+        for connected in [&mut reoriented, &mut rows] {
+            assert!(connected.token.is_cancelled());
+            // The new session sets the images, nothing is redrawn on the old one
+            assert!(connected.images.try_recv().is_err());
+            assert!(connected.device.commands().is_empty());
+        }
+
+        reoriented.disconnect().await;
+        rows.disconnect().await;
+    }
+
+    #[tokio::test]
+    async fn remap_and_layout_changes_redraw_without_registering() {
+        let mut remapped = Connected::new("reload-remap").await;
+        let mut relaid = Connected::new("reload-layout").await;
+        let mut untouched = Connected::new("reload-untouched").await;
+
+        let config = parse(
+            r#"
+            [devices.reload-remap]
+            remap = [[6, 7], [7, 6]]
+            brightness = 80
+
+            [devices.reload-layout.layout]
+            fit = "cover"
+            "#,
+        );
+        apply_config(&Config::default(), &config).await;
+
+        for connected in [&mut remapped, &mut relaid] {
+            assert!(!connected.token.is_cancelled());
+            assert!(matches!(
+                connected.images.try_recv(),
+                Ok(ImageCommand::ClearAll)
+            ));
+        }
+        assert_eq!(remapped.device.commands(), ["SetBrightness 80"]);
+        assert!(relaid.device.commands().is_empty());
+
+        assert!(!untouched.token.is_cancelled());
+        assert!(untouched.images.try_recv().is_err());
+        assert!(untouched.device.commands().is_empty());
+
+        remapped.disconnect().await;
+        relaid.disconnect().await;
+        untouched.disconnect().await;
+    }
+}
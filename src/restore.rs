@@ -0,0 +1,142 @@
+use openaction::SetImageEvent;
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use crate::device::handle_set_image;
+
+/// What OpenDeck last asked a device to show
+#[derive(Debug, Default)]
+struct Snapshot {
+    brightness: Option<u8>,
+    /// Data urls by UI position
+    images: HashMap<u8, String>,
+}
+
+/// Snapshots of all devices seen since start, keyed by device id. Kept after a device
+/// disconnects, so it looks the same as before once it is back
+static SNAPSHOTS: LazyLock<Mutex<HashMap<String, Snapshot>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Records image set at a UI position, `None` meaning the position was cleared
+pub fn record_image(id: &str, position: u8, image: Option<&str>) {
+    let mut snapshots = SNAPSHOTS.lock().unwrap();
+    let images = &mut snapshots.entry(id.to_string()).or_default().images;
+
+    match image {
+        Some(image) => images.insert(position, image.to_string()),
+        None => images.remove(&position),
+    };
+}
+
+/// Records that all images of a device were cleared
+pub fn record_images_cleared(id: &str) {
+    if let Some(snapshot) = SNAPSHOTS.lock().unwrap().get_mut(id) {
+        snapshot.images.clear();
+    }
+}
+
+pub fn record_brightness(id: &str, brightness: u8) {
+    SNAPSHOTS
+        .lock()
+        .unwrap()
+        .entry(id.to_string())
+        .or_default()
+        .brightness = Some(brightness);
+}
+
+/// Returns brightness last set on a device
+pub fn last_brightness(id: &str) -> Option<u8> {
+    SNAPSHOTS
+        .lock()
+        .unwrap()
+        .get(id)
+        .and_then(|snapshot| snapshot.brightness)
+}
+
+/// Shows the images a device had before it got reconnected
+pub async fn restore_images(id: &str) {
+    let images: Vec<(u8, String)> = match SNAPSHOTS.lock().unwrap().get(id) {
+        Some(snapshot) => snapshot
+            .images
+            .iter()
+            .map(|(position, image)| (*position, image.clone()))
+            .collect(),
+        None => return,
+    };
+
+    if images.is_empty() {
+        return;
+    }
+
+    log::info!("Restoring {} images of {}", images.len(), id);
+
+    for (position, image) in images {
+        let event = SetImageEvent {
+            device: id.to_string(),
+            controller: None,
+            position: Some(position),
+            image: Some(image),
+        };
+
+        if let Err(err) = handle_set_image(event).await {
+            log::warn!(
+                "Failed to restore image at position={} of {}: {}",
+                position,
+                id,
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fake::fake_device_info, mappings::KINDS, queue::register_image_queue,
+        watcher::device_info_to_candidate,
+    };
+
+    /// 1x1 PNG
+    const IMAGE: &str = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAIAAACQd1PeAAAADElEQVR4nGNgYGAAAAAEAAH2FzhVAAAAAElFTkSuQmCC";
+
+    fn recorded(id: &str, position: u8) -> Option<String> {
+        SNAPSHOTS
+            .lock()
+            .unwrap()
+            .get(id)
+            .and_then(|snapshot| snapshot.images.get(&position).cloned())
+    }
+
+    async fn set_image(id: &str, position: u8, image: &str) {
+        let event = SetImageEvent {
+            device: id.to_string(),
+            controller: None,
+            position: Some(position),
+            image: Some(image.to_string()),
+        };
+
+        handle_set_image(event).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_decoded_images_are_restored() {
+        let candidate = fake_device_info("restore", KINDS.first().unwrap())
+            .and_then(device_info_to_candidate)
+            .unwrap();
+        let _commands = register_image_queue(&candidate).await;
+        let id = candidate.id.as_str();
+
+        set_image(id, 6, IMAGE).await;
+        assert_eq!(recorded(id, 6).as_deref(), Some(IMAGE));
+
+        // A broken image replaces the earlier one, so nothing is left to restore
+        set_image(id, 6, "data:image/png;base64,AAAAAAAA").await;
+        assert_eq!(recorded(id, 6), None);
+
+        set_image(id, 7, "data:image/png;base64,AAAAAAAA").await;
+        assert_eq!(recorded(id, 7), None);
+    }
+}
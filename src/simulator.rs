@@ -0,0 +1,236 @@
+use image::{DynamicImage, Rgb, RgbImage, imageops, imageops::FilterType};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{Write, stderr},
+    path::PathBuf,
+    sync::LazyLock,
+};
+
+use crate::{fake::Command, mappings::Kind};
+
+/// Space around every key on the canvas
+const MARGIN: u32 = 8;
+const BACKGROUND: Rgb<u8> = Rgb([24, 24, 24]);
+/// Colour of keys without an image, and of input-only keys
+const BLANK_KEY: Rgb<u8> = Rgb([56, 56, 56]);
+/// Canvas pixels per terminal column in the preview, every terminal row shows two pixel rows
+const PREVIEW_SCALE: u32 = 8;
+
+/// Where simulated screens are shown
+#[derive(Debug, Clone)]
+pub enum Output {
+    /// PNG file per device in a directory
+    Png(PathBuf),
+    /// Preview on stderr, which is left alone by the logger
+    Terminal,
+}
+
+/// From `OPENDECK_AKP05_SIMULATOR_OUTPUT`, a directory or `-` for the terminal
+pub static OUTPUT: LazyLock<Option<Output>> = LazyLock::new(|| {
+    let raw = env::var("OPENDECK_AKP05_SIMULATOR_OUTPUT").ok()?;
+
+    match raw.trim() {
+        "" => None,
+        "-" => Some(Output::Terminal),
+        dir => Some(Output::Png(PathBuf::from(dir))),
+    }
+});
+
+/// Screens of a simulated device, laid out like the hardware with no mounting rotation applied
+pub struct Canvas {
+    serial: String,
+    kind: Kind,
+    output: Output,
+    /// Images by hardware target, as of the last flush
+    shown: HashMap<u8, DynamicImage>,
+    /// Changes since the last flush, `None` clearing a target
+    pending: HashMap<u8, Option<DynamicImage>>,
+}
+
+impl Canvas {
+    /// Returns canvas of a device if simulated screens are enabled
+    pub fn new(serial: &str, vid: u16, pid: u16) -> Option<Self> {
+        let output = OUTPUT.clone()?;
+        let kind = Kind::from_vid_pid(vid, pid)?;
+
+        if let Output::Png(dir) = &output
+            && let Err(err) = fs::create_dir_all(dir)
+        {
+            log::error!("Failed to create {}: {}", dir.display(), err);
+            return None;
+        }
+
+        Some(Self {
+            serial: serial.to_string(),
+            kind,
+            output,
+            shown: HashMap::new(),
+            pending: HashMap::new(),
+        })
+    }
+
+    /// Applies a command the device got, screens change on flush like on the hardware
+    pub fn apply(&mut self, command: Command) {
+        match command {
+            Command::SetImage(hw_pos, image) => {
+                self.pending.insert(hw_pos, Some(image));
+            }
+            Command::ClearImage(hw_pos) => {
+                self.pending.insert(hw_pos, None);
+            }
+            Command::ClearAll => {
+                self.pending = self.kind.hw_image_targets().map(|hw| (hw, None)).collect();
+            }
+            Command::Flush => self.flush(),
+            _ => {}
+        }
+    }
+
+    /// Applies changes since the last flush and shows the result
+    fn flush(&mut self) {
+        for (hw_pos, image) in self.pending.drain() {
+            match image {
+                Some(image) => self.shown.insert(hw_pos, image),
+                None => self.shown.remove(&hw_pos),
+            };
+        }
+
+        let canvas = self.render();
+
+        match &self.output {
+            Output::Png(dir) => {
+                let path = dir.join(format!("{}.png", self.serial));
+                // Written next to the final file first, so viewers never load half of it
+                let partial = dir.join(format!(".{}.png", self.serial));
+
+                if let Err(err) = canvas
+                    .save_with_format(&partial, image::ImageFormat::Png)
+                    .map_err(|err| err.to_string())
+                    .and_then(|_| fs::rename(&partial, &path).map_err(|err| err.to_string()))
+                {
+                    log::error!("Failed to write {}: {}", path.display(), err);
+                }
+            }
+            Output::Terminal => preview(&self.serial, &canvas),
+        }
+    }
+
+    /// Size of a grid cell, fitting the largest screen of the device
+    fn cell_size(&self) -> u32 {
+        self.kind
+            .hw_image_targets()
+            .map(|hw| {
+                let (width, height) = self.kind.image_size_for(hw);
+                width.max(height) as u32
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn render(&self) -> RgbImage {
+        let cell = self.cell_size();
+        let (rows, cols) = (self.kind.row_count() as u32, self.kind.col_count() as u32);
+
+        let mut canvas = RgbImage::from_pixel(
+            cols * (cell + MARGIN) + MARGIN,
+            rows * (cell + MARGIN) + MARGIN,
+            BACKGROUND,
+        );
+
+        let keys: Vec<u8> = (0..self.kind.key_count() as u8)
+            .filter_map(|key| self.kind.input_key_to_ui(key))
+            .collect();
+
+        for position in 0..(rows * cols) as u8 {
+            let (width, height) = match self.kind.image_position_to_hw(position) {
+                Some(Some(hw)) => {
+                    let (width, height) = self.kind.image_size_for(hw);
+                    (width as u32, height as u32)
+                }
+                // Input-only keys are drawn as blank keys of full size
+                Some(None) if keys.contains(&position) => (cell, cell),
+                _ => continue,
+            };
+
+            let tile = match self.kind.image_position_to_hw(position).flatten() {
+                Some(hw) if self.shown.contains_key(&hw) => self.shown[&hw]
+                    .resize_exact(width, height, FilterType::Triangle)
+                    .to_rgb8(),
+                _ => RgbImage::from_pixel(width, height, BLANK_KEY),
+            };
+
+            let (row, col) = (position as u32 / cols, position as u32 % cols);
+            let x = MARGIN + col * (cell + MARGIN) + (cell - width) / 2;
+            let y = MARGIN + row * (cell + MARGIN) + (cell - height) / 2;
+
+            imageops::overlay(&mut canvas, &tile, x as i64, y as i64);
+        }
+
+        canvas
+    }
+}
+
+/// Draws a canvas on stderr with half-block characters in 24-bit colour
+fn preview(serial: &str, canvas: &RgbImage) {
+    let width = canvas.width() / PREVIEW_SCALE;
+    let height = canvas.height() / PREVIEW_SCALE / 2 * 2;
+    let small = imageops::resize(canvas, width, height, FilterType::Triangle);
+
+    let mut out = format!("\x1b[2J\x1b[H{}\n", serial);
+
+    for row in (0..height).step_by(2) {
+        for col in 0..width {
+            let Rgb([tr, tg, tb]) = *small.get_pixel(col, row);
+            let Rgb([br, bg, bb]) = *small.get_pixel(col, row + 1);
+            out += &format!(
+                "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                tr, tg, tb, br, bg, bb
+            );
+        }
+        out += "\x1b[0m\n";
+    }
+
+    stderr().write_all(out.as_bytes()).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappings::KINDS;
+
+    const RED: Rgb<u8> = Rgb([200, 40, 40]);
+
+    #[test]
+    fn flush_writes_keys_laid_out_like_the_hardware() {
+        let dir = env::temp_dir().join(format!("opendeck-n1-canvas-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut canvas = Canvas {
+            serial: "canvas".to_string(),
+            kind: KINDS.first().unwrap().clone(),
+            output: Output::Png(dir.clone()),
+            shown: HashMap::new(),
+            pending: HashMap::new(),
+        };
+
+        // Hardware key 0 is the top-left keypad key, position 6 below the three segments
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(96, 96, RED));
+        canvas.apply(Command::SetImage(0, image));
+        canvas.apply(Command::Flush);
+
+        let png = image::open(dir.join("canvas.png")).unwrap().to_rgb8();
+        fs::remove_dir_all(&dir).ok();
+
+        // 3 columns and 7 rows of 96 pixel keys, with margins around each
+        assert_eq!(png.dimensions(), (3 * (96 + 8) + 8, 7 * (96 + 8) + 8));
+
+        let (x, y) = (MARGIN, MARGIN + 2 * (96 + MARGIN));
+        assert_eq!(*png.get_pixel(x, y), RED);
+        assert_eq!(*png.get_pixel(x + 95, y + 95), RED);
+        assert_eq!(*png.get_pixel(x - 1, y), BACKGROUND);
+        assert_eq!(*png.get_pixel(x + 96, y), BACKGROUND);
+        // Position 7 next to it has no image yet
+        assert_eq!(*png.get_pixel(x + 96 + MARGIN, y), BLANK_KEY);
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::{HashMap, hash_map::RandomState},
+    hash::{BuildHasher, Hasher},
+    sync::{
+        LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::{
+    sync::RwLock,
+    time::{Duration, Instant, sleep},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{device::device_task, hardware::Hardware, mappings::CandidateDevice};
+
+/// Delay before the first reconnect attempt
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Longest delay between reconnect attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Session running this long counts as recovered, so the next failure starts from a short delay again
+const STABLE_SESSION: Duration = Duration::from_secs(30);
+
+/// Tokens of running device sessions with their session numbers, keyed by device id.
+/// Cancelling one ends the session, while the supervisor keeps going and connects again
+static SESSIONS: LazyLock<RwLock<HashMap<String, (u64, CancellationToken)>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Number of the next session, telling sessions of the same device apart
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(0);
+
+/// Ends current session of a device, the supervisor decides whether to start another one
+pub async fn cancel_session(id: &str) {
+    if let Some((_, token)) = SESSIONS.read().await.get(id) {
+        token.cancel();
+    }
+}
+
+/// Makes a session the current one of a device, returning its number
+pub async fn begin_session(id: &str, token: CancellationToken) -> u64 {
+    let number = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+    SESSIONS
+        .write()
+        .await
+        .insert(id.to_string(), (number, token));
+
+    number
+}
+
+/// Forgets a session, unless a newer one of the device took its place in the meantime
+async fn end_session(id: &str, number: u64) {
+    let mut sessions = SESSIONS.write().await;
+
+    if sessions
+        .get(id)
+        .is_some_and(|(current, _)| *current == number)
+    {
+        sessions.remove(id);
+    }
+}
+
+/// Returns exponential backoff for an attempt, with up to half of it added as jitter
+/// so devices failing together don't reconnect in lockstep
+fn backoff(attempt: u32) -> Duration {
+    let base = INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF);
+
+    let random = RandomState::new().build_hasher().finish();
+    let jitter = base.mul_f64((random % 1000) as f64 / 2000.0);
+
+    base + jitter
+}
+
+/// Runs device sessions, connecting again with backoff whenever one fails while the device
+/// is still plugged in. Finishes when cancelled or once the device is gone
+pub async fn supervisor_task<H: Hardware + 'static>(
+    candidate: CandidateDevice,
+    token: CancellationToken,
+) {
+    let mut attempt = 0;
+
+    loop {
+        let session = token.child_token();
+        let number = begin_session(&candidate.id, session.clone()).await;
+
+        let started = Instant::now();
+        device_task::<H>(candidate.clone(), session).await;
+
+        end_session(&candidate.id, number).await;
+
+        if token.is_cancelled() {
+            break;
+        }
+
+        if started.elapsed() >= STABLE_SESSION {
+            attempt = 0;
+        }
+
+        if !H::is_present(&candidate.dev).await {
+            log::info!(
+                "Device {} is gone, waiting for it to be plugged in again",
+                candidate.id
+            );
+            break;
+        }
+
+        let delay = backoff(attempt);
+        attempt = attempt.saturating_add(1);
+
+        log::warn!(
+            "Device {} failed but is still present, reconnecting in {:?} (attempt {})",
+            candidate.id,
+            delay,
+            attempt
+        );
+
+        tokio::select! {
+            _ = sleep(delay) => {},
+            _ = token.cancelled() => break,
+        }
+    }
+
+    // Lets the watcher start a new supervisor once the device is plugged in again
+    token.cancel();
+
+    log::info!("Supervisor finished for {}", candidate.id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_up_to_cap_with_bounded_jitter() {
+        for attempt in 0..40 {
+            let base = INITIAL_BACKOFF
+                .saturating_mul(2u32.saturating_pow(attempt))
+                .min(MAX_BACKOFF);
+
+            for _ in 0..20 {
+                let delay = backoff(attempt);
+                assert!(
+                    delay >= base && delay < base.mul_f64(1.5),
+                    "attempt {} waits {:?}",
+                    attempt,
+                    delay
+                );
+            }
+        }
+
+        assert!(backoff(0) < Duration::from_millis(750));
+        assert!(backoff(3) >= Duration::from_secs(4));
+        assert!(backoff(7) >= MAX_BACKOFF);
+        assert!(backoff(u32::MAX) < MAX_BACKOFF.mul_f64(1.5));
+    }
+
+    #[tokio::test]
+    async fn ending_old_session_keeps_newer_one() {
+        let (old, new) = (CancellationToken::new(), CancellationToken::new());
+
+        let old_number = begin_session("sessions", old.clone()).await;
+        let new_number = begin_session("sessions", new.clone()).await;
+
+        end_session("sessions", old_number).await;
+        cancel_session("sessions").await;
+        assert!(new.is_cancelled());
+        assert!(!old.is_cancelled());
+
+        end_session("sessions", new_number).await;
+        assert!(!SESSIONS.read().await.contains_key("sessions"));
+    }
+}
@@ -1,6 +1,6 @@
 use futures_lite::StreamExt;
 use mirajazz::{
-    device::{DeviceWatcher, list_devices},
+    device::{Device, DeviceWatcher, list_devices},
     error::MirajazzError,
     types::{DeviceLifecycleEvent, HidDeviceInfo},
 };
@@ -9,15 +9,21 @@ use tokio_util::sync::CancellationToken;
 
 use crate::{
     DEVICES, TOKENS, TRACKER,
-    device::device_task,
-    mappings::{CandidateDevice, DEVICE_NAMESPACE, Kind, QUERIES},
+    fake::{FakeDevice, fake_device_info, fake_input_task, fake_serials},
+    mappings::{CandidateDevice, DEVICE_NAMESPACE, KINDS, Kind, QUERIES},
+    supervisor::supervisor_task,
 };
 
 fn serial_to_id(serial: &String) -> String {
     format!("{}-{}", DEVICE_NAMESPACE, serial)
 }
 
-fn device_info_to_candidate(dev: HidDeviceInfo) -> Option<CandidateDevice> {
+/// Returns serial number a device id was made from
+pub fn id_to_serial(id: &str) -> Option<&str> {
+    id.strip_prefix(DEVICE_NAMESPACE)?.strip_prefix('-')
+}
+
+pub fn device_info_to_candidate(dev: HidDeviceInfo) -> Option<CandidateDevice> {
     let id = serial_to_id(&dev.serial_number.clone()?);
     let kind = Kind::from_vid_pid(dev.vendor_id, dev.product_id)?;
 
@@ -36,12 +42,12 @@ fn device_info_to_candidate(dev: HidDeviceInfo) -> Option<CandidateDevice> {
 }
 
 /// Returns devices that matches known pid/vid pairs
-async fn get_candidates() -> Result<Vec<CandidateDevice>, MirajazzError> {
+pub async fn get_candidates() -> Result<Vec<CandidateDevice>, MirajazzError> {
     log::info!("Looking for candidate devices");
 
     let mut candidates: Vec<CandidateDevice> = Vec::new();
 
-    for dev in list_devices(&QUERIES).await? {
+    for dev in list_devices(QUERIES.as_slice()).await? {
         if let Some(candidate) = device_info_to_candidate(dev.clone()) {
             candidates.push(candidate);
         } else {
@@ -52,9 +58,42 @@ async fn get_candidates() -> Result<Vec<CandidateDevice>, MirajazzError> {
     Ok(candidates)
 }
 
+/// Connects fake devices asked for in the environment as the first known kind, before looking for real ones
+async fn spawn_fake_devices(token: &CancellationToken) {
+    let serials = fake_serials();
+    let Some(kind) = KINDS.first().filter(|_| !serials.is_empty()) else {
+        return;
+    };
+
+    let tracker = TRACKER.lock().await.clone();
+
+    for serial in serials {
+        let Some(candidate) = fake_device_info(&serial, kind).and_then(device_info_to_candidate)
+        else {
+            log::error!("Fake devices are not supported on this platform");
+            return;
+        };
+
+        log::info!("Adding fake device id={}", candidate.id);
+
+        let token = CancellationToken::new();
+
+        TOKENS
+            .write()
+            .await
+            .insert(candidate.id.clone(), token.clone());
+
+        tracker.spawn(supervisor_task::<FakeDevice>(candidate, token));
+    }
+
+    tracker.spawn(fake_input_task(token.clone()));
+}
+
 pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError> {
     let tracker = TRACKER.lock().await.clone();
 
+    spawn_fake_devices(&token).await;
+
     // Scans for connected devices that (possibly) we can use
     let candidates = get_candidates().await?;
 
@@ -76,11 +115,11 @@ pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError>
             .await
             .insert(candidate.id.clone(), token.clone());
 
-        tracker.spawn(device_task(candidate, token));
+        tracker.spawn(supervisor_task::<Device>(candidate, token));
     }
 
     let mut watcher = DeviceWatcher::new();
-    let mut watcher_stream = watcher.watch(&QUERIES).await?;
+    let mut watcher_stream = watcher.watch(QUERIES.as_slice()).await?;
 
     log::info!("Watcher is ready");
 
@@ -104,8 +143,13 @@ pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError>
                             .unwrap_or_else(|| "<none>".to_string())
                     );
                     if let Some(candidate) = device_info_to_candidate(info) {
-                        // Don't add existing device again
-                        if DEVICES.read().await.contains_key(&candidate.id) {
+                        // Don't add existing device again, its supervisor is still looking after it
+                        if TOKENS
+                            .read()
+                            .await
+                            .get(&candidate.id)
+                            .is_some_and(|token| !token.is_cancelled())
+                        {
                             log::debug!("Skipping duplicate connected event for {}", candidate.id);
                             continue;
                         }
@@ -118,11 +162,11 @@ pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError>
                             .insert(candidate.id.clone(), token.clone());
 
                         log::info!(
-                            "Spawning device task for id={} ({})",
+                            "Spawning supervisor for id={} ({})",
                             candidate.id,
                             candidate.kind.human_name()
                         );
-                        tracker.spawn(device_task(candidate, token));
+                        tracker.spawn(supervisor_task::<Device>(candidate, token));
                         log::debug!("Spawned");
                     }
                 }
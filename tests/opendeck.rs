@@ -0,0 +1,397 @@
+//! Stand-in for OpenDeck speaking the plugin websocket protocol. Launches the plugin binary
+//! against it with fake devices, so plugin behaviour can be checked end to end without hardware
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use futures_util::{SinkExt, StreamExt};
+use image::{ImageFormat, Rgb, RgbImage};
+use serde_json::{Value, json};
+use std::{env, fs, io::Cursor, path::PathBuf, process::Stdio, sync::LazyLock};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    process::{Child, ChildStdin, Command},
+    sync::mpsc::{UnboundedReceiver, unbounded_channel},
+    time::{Duration, timeout},
+};
+use tokio_tungstenite::{WebSocketStream, accept_async, tungstenite::Message};
+
+/// Manifest OpenDeck installs the plugin from, so the harness sends what OpenDeck would
+static MANIFEST: LazyLock<Value> = LazyLock::new(|| {
+    serde_json::from_str(include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/manifest.json"
+    )))
+    .unwrap()
+});
+
+const REGISTER_EVENT: &str = "registerPlugin";
+/// How long to wait for the plugin before failing
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// OpenDeck with the plugin connected to it
+pub struct FakeOpenDeck {
+    socket: WebSocketStream<TcpStream>,
+    plugin: Child,
+    /// Feeds input to fake devices
+    stdin: ChildStdin,
+    /// Lines the plugin logged
+    logs: UnboundedReceiver<String>,
+    config_dir: PathBuf,
+}
+
+fn plugin_uuid() -> &'static str {
+    MANIFEST["PluginUUID"].as_str().unwrap()
+}
+
+/// Returns id OpenDeck knows a device with a serial number by
+pub fn device_id(serial: &str) -> String {
+    format!(
+        "{}-{}",
+        MANIFEST["DeviceNamespace"].as_str().unwrap(),
+        serial
+    )
+}
+
+impl FakeOpenDeck {
+    /// Launches the plugin with fake devices and waits for it to register
+    pub async fn start(serials: &[&str]) -> Self {
+        Self::start_with_config(serials, None).await
+    }
+
+    /// Like [FakeOpenDeck::start], with contents of a config file for the plugin
+    pub async fn start_with_config(serials: &[&str], config: Option<&str>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Keeps the config of whoever runs the tests out of the way
+        let config_dir =
+            env::temp_dir().join(format!("opendeck-n1-test-{}-{}", std::process::id(), port));
+        fs::create_dir_all(config_dir.join("opendeck-n1")).unwrap();
+        if let Some(config) = config {
+            fs::write(config_dir.join("opendeck-n1").join("config.toml"), config).unwrap();
+        }
+
+        let mut plugin = Command::new(env!("CARGO_BIN_EXE_opendeck-n1"))
+            .args(["-port", &port.to_string()])
+            .args(["-pluginUUID", plugin_uuid()])
+            .args(["-registerEvent", REGISTER_EVENT])
+            .args(["-info", "{}"])
+            .env("OPENDECK_AKP05_FAKE_DEVICES", serials.join(","))
+            .env("OPENDECK_AKP05_LOG", "debug")
+            .env("XDG_CONFIG_HOME", &config_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .expect("failed to launch the plugin");
+
+        let stdin = plugin.stdin.take().unwrap();
+        let stdout = plugin.stdout.take().unwrap();
+
+        let (sender, logs) = unbounded_channel();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                sender.send(line).ok();
+            }
+        });
+
+        let (stream, _) = timeout(TIMEOUT, listener.accept())
+            .await
+            .expect("plugin did not connect")
+            .unwrap();
+        let socket = accept_async(stream).await.unwrap();
+
+        let mut opendeck = Self {
+            socket,
+            plugin,
+            stdin,
+            logs,
+            config_dir,
+        };
+
+        let register = opendeck.next_event().await;
+        assert_eq!(register["event"], REGISTER_EVENT);
+        assert_eq!(register["uuid"], plugin_uuid());
+
+        opendeck
+    }
+
+    /// Returns next event the plugin sent
+    pub async fn next_event(&mut self) -> Value {
+        loop {
+            let message = timeout(TIMEOUT, self.socket.next())
+                .await
+                .expect("no event from the plugin")
+                .expect("plugin closed the connection")
+                .unwrap();
+
+            if let Message::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    /// Skips events until one with a name arrives, returning its payload
+    pub async fn expect_event(&mut self, event: &str) -> Value {
+        loop {
+            let mut next = self.next_event().await;
+            if next["event"] == event {
+                return next["payload"].take();
+            }
+        }
+    }
+
+    /// Waits for `registerDevice` of a fake device
+    pub async fn expect_device(&mut self, serial: &str) -> Value {
+        loop {
+            let payload = self.expect_event("registerDevice").await;
+            if payload["id"] == device_id(serial) {
+                return payload;
+            }
+        }
+    }
+
+    /// Skips logged lines until a fake device logs a command starting with `command`,
+    /// e.g. `SetBrightness 40`, returning the whole command
+    pub async fn expect_command(&mut self, serial: &str, command: &str) -> String {
+        let prefix = format!("FAKE {} ", serial);
+
+        loop {
+            let line = timeout(TIMEOUT, self.logs.recv())
+                .await
+                .unwrap_or_else(|_| panic!("no {} command from {}", command, serial))
+                .expect("plugin exited");
+
+            if let Some(logged) = line.split_once(&prefix).map(|(_, logged)| logged)
+                && logged.starts_with(command)
+            {
+                return logged.to_string();
+            }
+        }
+    }
+
+    /// Sends an event to the plugin
+    pub async fn send(&mut self, event: Value) {
+        self.socket
+            .send(Message::Text(event.to_string().into()))
+            .await
+            .unwrap();
+    }
+
+    pub async fn set_image(&mut self, serial: &str, position: Option<u8>, image: Option<&str>) {
+        self.send(json!({
+            "event": "setImage",
+            "device": device_id(serial),
+            "controller": null,
+            "position": position,
+            "image": image,
+        }))
+        .await;
+    }
+
+    pub async fn set_brightness(&mut self, serial: &str, brightness: u8) {
+        self.send(json!({
+            "event": "setBrightness",
+            "device": device_id(serial),
+            "brightness": brightness,
+        }))
+        .await;
+    }
+
+    async fn write_input(&mut self, line: String) {
+        self.stdin
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .unwrap();
+        self.stdin.flush().await.unwrap();
+    }
+
+    /// Makes a fake device report a raw `(input, state)` pair, as the device would
+    pub async fn raw_input(&mut self, serial: &str, input: u8, state: u8) {
+        self.write_input(format!("{} {} {}", serial, input, state))
+            .await;
+    }
+
+    /// Makes the next operation on a fake device fail
+    pub async fn fail(&mut self, serial: &str) {
+        self.write_input(format!("{} fail", serial)).await;
+    }
+}
+
+/// Returns a solid colour PNG as data url, the way OpenDeck sends images
+pub fn png_data_url(width: u32, height: u32) -> String {
+    let image = RgbImage::from_pixel(width, height, Rgb([200, 40, 40]));
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageFormat::Png).unwrap();
+
+    format!(
+        "data:image/png;base64,{}",
+        STANDARD.encode(png.into_inner())
+    )
+}
+
+impl Drop for FakeOpenDeck {
+    fn drop(&mut self) {
+        self.plugin.start_kill().ok();
+        fs::remove_dir_all(&self.config_dir).ok();
+    }
+}
+
+/// Raw input of the top-left keypad key, shown at position 6
+const KEY: u8 = 0x01;
+const KEY_POSITION: u64 = 6;
+/// Raw input of the encoder turning clockwise and being pressed
+const ENCODER_CW: u8 = 0x33;
+const ENCODER_PRESS: u8 = 0x23;
+
+#[tokio::test]
+async fn registers_fake_device() {
+    let mut opendeck = FakeOpenDeck::start(&["register"]).await;
+
+    let device = opendeck.expect_device("register").await;
+    assert_eq!(device["rows"], 7);
+    assert_eq!(device["columns"], 3);
+    assert_eq!(device["encoders"], 1);
+
+    opendeck.expect_command("register", "SetMode 3").await;
+    opendeck.expect_command("register", "ClearAll").await;
+}
+
+#[tokio::test]
+async fn sends_key_and_encoder_events() {
+    let mut opendeck = FakeOpenDeck::start(&["input"]).await;
+    opendeck.expect_device("input").await;
+
+    opendeck.raw_input("input", KEY, 1).await;
+    let down = opendeck.expect_event("keyDown").await;
+    assert_eq!(down["device"], device_id("input"));
+    assert_eq!(down["position"], KEY_POSITION);
+
+    opendeck.raw_input("input", KEY, 0).await;
+    let up = opendeck.expect_event("keyUp").await;
+    assert_eq!(up["position"], KEY_POSITION);
+
+    opendeck.raw_input("input", ENCODER_CW, 1).await;
+    let change = opendeck.expect_event("encoderChange").await;
+    assert_eq!(change["device"], device_id("input"));
+    assert_eq!(change["position"], 0);
+    assert_eq!(change["ticks"], 1);
+
+    opendeck.raw_input("input", ENCODER_PRESS, 1).await;
+    assert_eq!(opendeck.expect_event("encoderDown").await["position"], 0);
+    opendeck.raw_input("input", ENCODER_PRESS, 0).await;
+    assert_eq!(opendeck.expect_event("encoderUp").await["position"], 0);
+}
+
+#[tokio::test]
+async fn applies_brightness() {
+    let mut opendeck = FakeOpenDeck::start(&["brightness"]).await;
+    opendeck.expect_device("brightness").await;
+
+    opendeck.set_brightness("brightness", 40).await;
+    opendeck
+        .expect_command("brightness", "SetBrightness 40")
+        .await;
+}
+
+#[tokio::test]
+async fn shows_and_clears_images() {
+    let mut opendeck = FakeOpenDeck::start(&["image"]).await;
+    opendeck.expect_device("image").await;
+
+    // Position 6 is the first keypad key, hardware key 0
+    opendeck
+        .set_image("image", Some(6), Some(&png_data_url(72, 72)))
+        .await;
+    assert_eq!(
+        opendeck.expect_command("image", "SetImage 0").await,
+        "SetImage 0 96x96"
+    );
+    opendeck.expect_command("image", "Flush").await;
+
+    opendeck.set_image("image", Some(6), None).await;
+    opendeck.expect_command("image", "ClearImage 0").await;
+    opendeck.expect_command("image", "Flush").await;
+}
+
+#[tokio::test]
+async fn reconnects_failed_device_and_restores_images() {
+    let mut opendeck = FakeOpenDeck::start(&["reconnect"]).await;
+    opendeck.expect_device("reconnect").await;
+
+    opendeck
+        .set_image("reconnect", Some(6), Some(&png_data_url(96, 96)))
+        .await;
+    opendeck.expect_command("reconnect", "SetImage 0").await;
+
+    // The upload of the next image fails, taking the device down
+    opendeck.fail("reconnect").await;
+    opendeck
+        .set_image("reconnect", Some(7), Some(&png_data_url(96, 96)))
+        .await;
+    assert_eq!(
+        opendeck.expect_event("deregisterDevice").await,
+        device_id("reconnect")
+    );
+
+    opendeck.expect_device("reconnect").await;
+    opendeck.expect_command("reconnect", "ClearAll").await;
+    // Both images come back in any order, including the one that failed to upload
+    let mut restored = [
+        opendeck.expect_command("reconnect", "SetImage").await,
+        opendeck.expect_command("reconnect", "SetImage").await,
+    ];
+    restored.sort();
+    assert_eq!(restored, ["SetImage 0 96x96", "SetImage 1 96x96"]);
+}
+
+#[tokio::test]
+async fn registers_devices_as_configured() {
+    let mut opendeck = FakeOpenDeck::start_with_config(
+        &["sideways", "remapped"],
+        Some(
+            r#"
+            brightness = 70
+
+            [devices.sideways]
+            orientation = 90
+
+            [devices.sideways.gestures]
+            virtual_rows = 1
+
+            [devices.remapped]
+            remap = [[6, 7], [7, 6]]
+            "#,
+        ),
+    )
+    .await;
+
+    // Devices connect in any order
+    let mut grids = Vec::new();
+    for _ in 0..2 {
+        let device = opendeck.expect_event("registerDevice").await;
+        grids.push((
+            device["id"].clone(),
+            device["rows"].clone(),
+            device["columns"].clone(),
+        ));
+    }
+    grids.sort_by_key(|(id, _, _)| id.to_string());
+    assert_eq!(
+        grids,
+        [
+            (json!(device_id("remapped")), json!(7), json!(3)),
+            // Turned on its side with a virtual row below the keys
+            (json!(device_id("sideways")), json!(4), json!(7)),
+        ]
+    );
+    opendeck
+        .expect_command("sideways", "SetBrightness 70")
+        .await;
+
+    opendeck.raw_input("remapped", KEY, 1).await;
+    let down = opendeck.expect_event("keyDown").await;
+    assert_eq!(down["device"], device_id("remapped"));
+    assert_eq!(down["position"], 7);
+}